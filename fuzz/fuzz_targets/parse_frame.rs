@@ -0,0 +1,40 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// piccp is a binary so pull the frame modules in directly
+#[allow(dead_code)]
+#[path = "../../src/frame.rs"]
+mod frame;
+#[allow(dead_code)]
+#[path = "../../src/handshake.rs"]
+mod handshake;
+#[allow(dead_code)]
+#[path = "../../src/hash.rs"]
+mod hash;
+#[allow(dead_code)]
+#[path = "../../src/identity.rs"]
+mod identity;
+#[allow(dead_code)]
+#[path = "../../src/metadata.rs"]
+mod metadata;
+#[allow(dead_code)]
+#[path = "../../src/pairing.rs"]
+mod pairing;
+#[allow(dead_code)]
+#[path = "../../src/reason.rs"]
+mod reason;
+#[allow(dead_code)]
+#[path = "../../src/session.rs"]
+mod session;
+
+use frame::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Frame::parse(data);
+    for v1 in [false, true] {
+        if let Ok(frame) = Frame::decode(data.to_vec(), v1) {
+            let _ = frame.parsed();
+        }
+    }
+});
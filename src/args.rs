@@ -1,4 +1,10 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use qrcode::EcLevel;
+
+use crate::codec::{parse_ec_level, Rendering};
+use crate::handshake::Compression;
+use crate::hash::{Hash, parse_hex};
+use crate::symbology::CodeKind;
 
 /// pic copy.  Copy files using pictures!
 ///
@@ -9,15 +15,54 @@ pub struct Args {
     #[clap(short='s', long)]
     pub send: bool,
 
-    /// Send data from this file
-    #[clap(short='i', long, default_value = "")]
-    pub input_file: String,
+    /// Send these files or directories.  More than one file, or a directory, is sent with a manifest.
+    #[clap(short='i', long, multiple_occurrences(true), multiple_values(true))]
+    pub input_file: Vec<String>,
+
+    /// The maximum size for each fragment, by default as much as fits in a frame
+    #[clap(short='f', long, env="PICCP_FRAGMENT_SIZE")]
+    pub fragment_size: Option<u16>,
+
+    /// The largest frame we can reliably show and scan, including headers, by default the largest code that fits the terminal
+    #[clap(short='m', long, env="PICCP_MAX_PAYLOAD")]
+    pub max_payload: Option<u16>,
+
+    /// The number of segments the sender may have outstanding at once
+    #[clap(short='w', long, env="PICCP_WINDOW_SIZE", default_value_t = 8)]
+    pub window_size: u16,
+
+    /// How long each segment is shown before moving to the next in milliseconds
+    #[clap(short='t', long, env="PICCP_FRAME_INTERVAL", default_value_t = 250)]
+    pub frame_interval: u32,
+
+    /// How long to wait for the other side in milliseconds before showing it where we are again, 0 waits forever
+    #[clap(short='T', long, env="PICCP_TIMEOUT", default_value_t = 5000)]
+    pub timeout: u32,
+
+    /// Broadcast without waiting for the receiver.  Both sides need this.
+    #[clap(short='b', long, env="PICCP_BROADCAST")]
+    pub broadcast: bool,
 
-    /// The maximum size for each fragment
-    #[clap(short='f', long, env="PICCP_FRAGMENT_SIZE", default_value_t = 128)]
-    pub fragment_size: u16,
+    /// Compress the data with none, deflate or zstd.  Data that doesn't compress is sent as is.
+    /// Only uncompressed transfers can be resumed if they're interrupted, so a receiver writing
+    /// to a file asks for the data as is, broadcasts need this set to none on the sending side.
+    #[clap(short='c', long, env="PICCP_COMPRESSION", default_value = "zstd")]
+    pub compression: Compression,
 
-    /// The width of a block
+    /// Encrypt the data with this passphrase, better given in the environment.  Both sides need this.
+    #[clap(short='p', long, env="PICCP_PASSPHRASE", hide_env_values = true)]
+    pub passphrase: Option<String>,
+
+    /// Send an endless stream of fountain coded symbols instead of numbered segments.  Both sides need this.
+    #[clap(long, env="PICCP_FOUNTAIN")]
+    pub fountain: bool,
+
+    /// Draw each module as a block of characters, two or four modules to a character with half or quadrant,
+    /// or as a bitmap with sixel, kitty, or bitmap for whichever the terminal has
+    #[clap(short='R', long, env="PICCP_RENDERING", default_value = "block")]
+    pub rendering: Rendering,
+
+    /// The width of a block, or of a bitmap module in pixels
     #[clap(short='W', long, env="PICCP_BLOCK_WIDTH", default_value_t = 4)]
     pub scale_width: u8,
 
@@ -25,6 +70,35 @@ pub struct Args {
     #[clap(short='H', long, env="PICCP_BLOCK_HEIGHT", default_value_t = 2)]
     pub scale_height: u8,
 
+    /// How much of a code can be lost and still be read: L, M, Q or H
+    #[clap(short='E', long, env="PICCP_EC_LEVEL", default_value = "L", parse(try_from_str = parse_ec_level))]
+    pub ec_level: EcLevel,
+
+    /// Show every code at the size of the largest frame, so it doesn't change size as frames do
+    #[clap(short='F', long, env="PICCP_FIXED_VERSION")]
+    pub fixed_version: bool,
+
+    /// Show this many codes side by side, each carrying its own frame, or as many as fit with 0.
+    /// The window should be at least this big.
+    #[clap(short='L', long, env="PICCP_LANES", default_value_t = 1)]
+    pub lanes: usize,
+
+    /// Lay three frames over each other in each code, one in each of red, green and blue.  Needs a
+    /// truecolor terminal and a colour camera.  Codes stay black and white unless the other side
+    /// has this too, and both sides of a broadcast need it.
+    #[clap(long, env="PICCP_RGB")]
+    pub rgb: bool,
+
+    /// Show qr, datamatrix or aztec codes, or qr if the other side can't read them.  Data Matrix
+    /// and Aztec need piccp built with --features rxing.
+    #[clap(long, env="PICCP_SYMBOLOGY", default_value = "qr")]
+    pub symbology: CodeKind,
+
+    /// Read frames from a piccp that only has v1 frames.  They have no crc, so other codes in
+    /// view can be taken for them, and they're ignored once a session has been joined.
+    #[clap(long, env="PICCP_V1")]
+    pub v1: bool,
+
     /// Hide quiet zone?
     #[clap(short='Q', long, env="PICCP_HIDE_QUIET_ZONE")]
     pub hide_quiet_zone: bool,
@@ -33,9 +107,60 @@ pub struct Args {
     #[clap(short='r', long)]
     pub receive: bool,
 
-    /// Receive data and write to this file
+    /// Receive data and write to this file, or into this directory
     #[clap(short='o', long, default_value = "")]
     pub output_file: String,
+
+    /// Give received files the permissions they had on the sender, less any setuid, setgid or
+    /// sticky bit
+    #[clap(long, env="PICCP_KEEP_MODE")]
+    pub keep_mode: bool,
+
+    /// The SHA-256 the received data should have, in hex
+    #[clap(short='e', long, env="PICCP_EXPECT_HASH", parse(try_from_str = parse_hex))]
+    pub expect_hash: Option<Hash>,
+
+    /// Where our identity and trusted keys are kept, ~/.piccp by default
+    #[clap(short='k', long, env="PICCP_KEYSTORE", default_value = "")]
+    pub keystore: String,
+
+    /// Don't compare pairing codes, for peers that can't show one.  Anything between the two
+    /// screens can then take part unnoticed.  Both sides need this.  Pairing only checks who
+    /// is on the other end at the start, it doesn't authenticate the frames that follow, use
+    /// --passphrase or a signature for that.
+    #[clap(long, env="PICCP_NO_PAIRING")]
+    pub no_pairing: bool,
+
+    /// Trust a key we haven't seen before the first time it signs something
+    #[clap(long, env="PICCP_TRUST_ON_FIRST_USE")]
+    pub trust_on_first_use: bool,
+
+    /// Refuse anything that isn't signed, as we do anyway once we trust someone's key
+    #[clap(long, env="PICCP_REQUIRE_SIGNATURE", conflicts_with = "allow-unsigned")]
+    pub require_signature: bool,
+
+    /// Accept unsigned data even though we trust someone's key
+    #[clap(long, env="PICCP_ALLOW_UNSIGNED")]
+    pub allow_unsigned: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Generate an identity key to sign what we send with
+    Keygen {
+        /// Replace the identity we have
+        #[clap(long)]
+        force: bool,
+    },
+    /// Show our public key as a code for a peer to import
+    ExportKey,
+    /// Scan a peer's public key and trust it under this name, which can't have spaces
+    ImportKey {
+        name: String,
+    },
 }
 
 impl Args {
@@ -5,14 +5,18 @@ use nokhwa::{CameraFormat, FrameFormat, Resolution};
 use tokio::sync::mpsc::unbounded_channel;
 
 use crate::codec::Decoder;
-use crate::Transport;
+use crate::frame::Frame;
 
 pub struct Camera {
     done: Arc<AtomicBool>
 }
 
 impl Camera {
-    pub fn new(transport: Transport, mut codec: Decoder) -> Self {
+    ///
+    /// Scan codes until dropped, handing every frame found to `handler`.
+    ///
+    pub fn new<F>(handler: F, mut codec: Decoder) -> Self
+        where F: Fn(Frame) + Send + 'static {
         let done = Arc::new(AtomicBool::new(false));
         let my_done = done.clone();
         let (tx, mut rx) = unbounded_channel();
@@ -61,7 +65,7 @@ impl Camera {
             loop {
                 let frames = rx.recv().await.unwrap();
                 for frame in frames {
-                    transport.receive_frame(frame);
+                    handler(frame);
                 }
             }
         });
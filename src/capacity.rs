@@ -0,0 +1,187 @@
+use qrcode::EcLevel;
+
+use crate::codec::Encoder;
+use crate::frame::SEGMENT_OVERHEAD;
+use crate::symbology::Symbology;
+
+/// The margin and borders around the code
+const BORDER_COLUMNS: u32 = 2 + 2;
+/// The margin and borders around the code, and the progress and log boxes below it
+const BORDER_ROWS: u32 = 2 + 2 + 3;
+/// Between codes side by side or one above the other
+pub const TILE_GAP: u32 = 1;
+
+///
+/// How big a code is and how many bytes of frame it holds.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    pub version: i16,
+    pub payload: usize,
+}
+
+impl Capacity {
+    pub fn of(symbology: &dyn Symbology, version: i16, ec_level: EcLevel) -> Self {
+        return Self {
+            version,
+            payload: symbology.payload(version, ec_level),
+        };
+    }
+
+    pub fn largest(symbology: &dyn Symbology, ec_level: EcLevel) -> Self {
+        return Self::of(symbology, symbology.max_version(), ec_level);
+    }
+
+    ///
+    /// The smallest code that holds `payload` bytes, if any does.
+    ///
+    pub fn holding(symbology: &dyn Symbology, payload: usize, ec_level: EcLevel) -> Option<Self> {
+        return (1..=symbology.max_version())
+            .map(|version| Self::of(symbology, version, ec_level))
+            .find(|capacity| capacity.payload >= payload);
+    }
+
+    ///
+    /// The most data a segment frame this size has room for.
+    ///
+    pub fn fragment_size(&self) -> usize {
+        return self.payload.saturating_sub(SEGMENT_OVERHEAD);
+    }
+}
+
+///
+/// Codes in a grid, each a lane of its own.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tiling {
+    pub capacity: Capacity,
+    pub across: usize,
+    pub lanes: usize,
+}
+
+impl Tiling {
+    ///
+    /// The grid of up to `lanes` codes, or as many as fit with 0, that fits a terminal
+    /// `columns` by `rows` and carries the most.  Each code holds at least `payload`.
+    ///
+    pub fn fitting(columns: u16, rows: u16, lanes: usize, payload: usize, encoder: &Encoder) -> Option<Self> {
+        let columns = (columns as u32 + TILE_GAP).saturating_sub(BORDER_COLUMNS);
+        let rows = (rows as u32 + TILE_GAP).saturating_sub(BORDER_ROWS);
+        return (1..=encoder.symbology().max_version())
+            .filter_map(|version| {
+                let capacity = Capacity::of(encoder.symbology(), version, encoder.ec_level());
+                let (width, height) = encoder.size(version);
+                let mut across = (columns / (width + TILE_GAP)) as usize;
+                let mut down = (rows / (height + TILE_GAP)) as usize;
+                if lanes > 0 {
+                    across = across.min(lanes);
+                    down = down.min((lanes + across.max(1) - 1) / across.max(1));
+                }
+                if capacity.payload < payload || across == 0 || down == 0 {
+                    return None;
+                }
+                let lanes = if lanes > 0 { (across * down).min(lanes) } else { across * down };
+                return Some(Self {
+                    capacity,
+                    across,
+                    lanes,
+                });
+            })
+            .max_by_key(|tiling| (tiling.capacity.payload * tiling.lanes, tiling.capacity.version));
+    }
+
+    ///
+    /// A grid that fits wherever both this one and `other` do, for frames that may be shown in
+    /// either kind of code.  It holds the least of the two, with no more codes across and no
+    /// more rows of them than either.
+    ///
+    pub fn within(&self, other: &Tiling) -> Self {
+        let across = self.across.min(other.across);
+        let rows = self.rows().min(other.rows());
+        return Self {
+            capacity: if self.capacity.payload <= other.capacity.payload { self.capacity } else { other.capacity },
+            across,
+            lanes: self.lanes.min(other.lanes).min(across * rows),
+        };
+    }
+
+    fn rows(&self) -> usize {
+        return (self.lanes + self.across - 1) / self.across;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Rendering;
+    use crate::symbology::CodeKind;
+
+    fn encoder() -> Encoder {
+        return Encoder::new(2, 1, true, EcLevel::L, Rendering::Block, false, CodeKind::Qr).unwrap();
+    }
+
+    ///
+    /// Whether `tiling` fits a terminal `columns` by `rows`, borders and all.
+    ///
+    fn fits(tiling: &Tiling, columns: u16, rows: u16) -> bool {
+        let (width, height) = encoder().size(tiling.capacity.version);
+        let down = (tiling.lanes + tiling.across - 1) / tiling.across;
+        return tiling.across as u32 * (width + TILE_GAP) <= columns as u32 + TILE_GAP - BORDER_COLUMNS
+            && down as u32 * (height + TILE_GAP) <= rows as u32 + TILE_GAP - BORDER_ROWS;
+    }
+
+    #[test]
+    fn the_smallest_code_that_holds_a_payload() {
+        let encoder = encoder();
+        let qr = encoder.symbology();
+        assert_eq!(Capacity::holding(qr, 0, EcLevel::L).unwrap().version, 1);
+        for payload in [1, 17, 18, 100, 1000, Capacity::largest(qr, EcLevel::L).payload] {
+            let capacity = Capacity::holding(qr, payload, EcLevel::L).unwrap();
+            assert!(capacity.payload >= payload);
+            assert!(capacity.version == 1 || Capacity::of(qr, capacity.version - 1, EcLevel::L).payload < payload);
+        }
+        assert_eq!(Capacity::holding(qr, Capacity::largest(qr, EcLevel::L).payload + 1, EcLevel::L), None);
+        assert!(Capacity::of(qr, 10, EcLevel::H).payload < Capacity::of(qr, 10, EcLevel::L).payload);
+    }
+
+    #[test]
+    fn the_largest_grid_that_fits_the_terminal() {
+        let one = Tiling::fitting(200, 60, 1, 0, &encoder()).unwrap();
+        assert_eq!((one.across, one.lanes), (1, 1));
+        assert!(fits(&one, 200, 60));
+        let bigger = Tiling { capacity: Capacity::of(encoder().symbology(), one.capacity.version + 1, EcLevel::L), ..one };
+        assert!(!fits(&bigger, 200, 60));
+        let many = Tiling::fitting(200, 60, 0, 0, &encoder()).unwrap();
+        assert!(many.lanes > 1 && fits(&many, 200, 60));
+        assert!(many.capacity.payload * many.lanes >= one.capacity.payload);
+        let three = Tiling::fitting(200, 60, 3, 0, &encoder()).unwrap();
+        assert!(three.lanes <= 3 && fits(&three, 200, 60));
+    }
+
+    #[test]
+    fn nothing_fits_a_tiny_terminal_or_an_unheld_payload() {
+        assert_eq!(Tiling::fitting(20, 10, 1, 0, &encoder()), None);
+        let one = Tiling::fitting(200, 60, 1, 0, &encoder()).unwrap();
+        assert_eq!(Tiling::fitting(200, 60, 1, one.capacity.payload + 1, &encoder()), None);
+        assert_eq!(Tiling::fitting(200, 60, 1, one.capacity.payload, &encoder()), Some(one));
+    }
+
+    fn tiling(version: i16, payload: usize, across: usize, lanes: usize) -> Tiling {
+        return Tiling {
+            capacity: Capacity { version, payload },
+            across,
+            lanes,
+        };
+    }
+
+    #[test]
+    fn a_grid_within_two_others_fits_both() {
+        let wide = tiling(10, 200, 4, 4);
+        let tall = tiling(12, 300, 1, 4);
+        assert_eq!(wide.within(&tall), tiling(10, 200, 1, 1));
+        assert_eq!(tall.within(&wide), tiling(10, 200, 1, 1));
+        let square = tiling(8, 150, 2, 4);
+        assert_eq!(square.within(&tiling(9, 180, 3, 5)), tiling(8, 150, 2, 4));
+        assert_eq!(square.within(&square), square);
+    }
+}
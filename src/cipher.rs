@@ -0,0 +1,181 @@
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::Aead;
+
+/// What encryption adds to every segment
+pub const TAG_SIZE: usize = 16;
+
+/// Keeps the nonces of segments and fountain symbols apart
+const SEGMENT_NONCE: u32 = 0;
+const SYMBOL_NONCE: u32 = 1;
+/// Sealed frames are told apart by their type and sequence
+const FRAME_NONCE: u32 = 0x100;
+
+///
+/// ChaCha20-Poly1305 keyed from a passphrase both sides were given.  The key is derived
+/// with Argon2 salted with the session id, so it's only worked out once the session is
+/// known and every transfer gets its own.  Each segment's nonce comes from its offset.
+/// Without a passphrase everything goes out as is.
+///
+/// Argon2 is slow on purpose, so the key is worked out off the async tasks by `unlock`
+/// before anything is sealed or opened.
+///
+pub struct Cipher {
+    passphrase: Option<String>,
+    key: Option<(u32, ChaCha20Poly1305)>,
+}
+
+impl Cipher {
+    pub fn new(passphrase: Option<String>) -> Self {
+        return Self {
+            passphrase,
+            key: None,
+        };
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        return self.passphrase.is_some();
+    }
+
+    ///
+    /// Work out the key for a session, if we haven't already.
+    ///
+    pub async fn unlock(&mut self, session: u32) {
+        let passphrase = match &self.passphrase {
+            Some(passphrase) if self.key.as_ref().map(|(id, _)| *id) != Some(session) => passphrase.clone(),
+            _ => return,
+        };
+        let key = tokio::task::spawn_blocking(move || {
+            let mut salt = b"piccp".to_vec();
+            salt.extend_from_slice(&session.to_be_bytes());
+            let mut key = [0u8; 32];
+            Argon2::default().hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                .expect("Argon2 rejected its own defaults");
+            return key;
+        }).await.expect("Argon2 panicked");
+        self.key = Some((session, ChaCha20Poly1305::new(Key::from_slice(&key))));
+    }
+
+    ///
+    /// The key for a session, none without a passphrase.  Sealing or opening anything before
+    /// `unlock` is an error rather than going out in the clear.
+    ///
+    fn key(&self, session: u32) -> Result<Option<&ChaCha20Poly1305>, String> {
+        if self.passphrase.is_none() {
+            return Ok(None);
+        }
+        return match &self.key {
+            Some((id, key)) if *id == session => Ok(Some(key)),
+            _ => Err(format!("No key was worked out for session {:08x}", session)),
+        };
+    }
+
+    fn nonce(kind: u32, id: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[0..4].copy_from_slice(&kind.to_be_bytes());
+        nonce[4..12].copy_from_slice(&id.to_be_bytes());
+        return *Nonce::from_slice(&nonce);
+    }
+
+    fn seal(&mut self, session: u32, kind: u32, id: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+        return match self.key(session)? {
+            Some(key) => key.encrypt(&Self::nonce(kind, id), data).map_err(|_| "too large to encrypt".to_string()),
+            None => Ok(data.to_vec()),
+        };
+    }
+
+    fn open(&mut self, session: u32, kind: u32, id: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+        let key = self.key(session)?.ok_or("The sender encrypted the data, set --passphrase")?;
+        return key.decrypt(&Self::nonce(kind, id), data)
+            .map_err(|_| "failed authentication, check the passphrase".to_string());
+    }
+
+    pub fn seal_segment(&mut self, session: u32, segment_offset: usize, data: &[u8]) -> Result<Vec<u8>, String> {
+        return self.seal(session, SEGMENT_NONCE, segment_offset as u64, data)
+            .map_err(|err| format!("Segment {}: {}", segment_offset, err));
+    }
+
+    pub fn open_segment(&mut self, session: u32, segment_offset: usize, data: &[u8]) -> Result<Vec<u8>, String> {
+        return self.open(session, SEGMENT_NONCE, segment_offset as u64, data)
+            .map_err(|err| format!("Segment {}: {}", segment_offset, err));
+    }
+
+    ///
+    /// Fountain symbols keep their 4 byte payload id in the clear, it's their nonce.
+    ///
+    pub fn seal_symbol(&mut self, session: u32, symbol: &[u8]) -> Result<Vec<u8>, String> {
+        let (id, data) = symbol.split_at(4);
+        let mut sealed = id.to_vec();
+        sealed.extend(self.seal(session, SYMBOL_NONCE, u32::from_be_bytes(id.try_into().unwrap()) as u64, data)
+            .map_err(|err| format!("Symbol: {}", err))?);
+        return Ok(sealed);
+    }
+
+    pub fn open_symbol(&mut self, session: u32, symbol: &[u8]) -> Result<Vec<u8>, String> {
+        let (id, data) = symbol.split_at(4);
+        let mut opened = id.to_vec();
+        opened.extend(self.open(session, SYMBOL_NONCE, u32::from_be_bytes(id.try_into().unwrap()) as u64, data)
+            .map_err(|err| format!("Symbol: {}", err))?);
+        return Ok(opened);
+    }
+
+    ///
+    /// The body of a frame that says something about the data, like its name or hash.
+    ///
+    pub fn seal_frame(&mut self, session: u32, frame_type: u8, sequence: u32, body: &[u8]) -> Result<Vec<u8>, String> {
+        return self.seal(session, FRAME_NONCE | frame_type as u32, sequence as u64, body)
+            .map_err(|err| format!("Frame {}: {}", sequence, err));
+    }
+
+    pub fn open_frame(&mut self, session: u32, frame_type: u8, sequence: u32, body: &[u8]) -> Result<Vec<u8>, String> {
+        return self.open(session, FRAME_NONCE | frame_type as u32, sequence as u64, body)
+            .map_err(|err| format!("Frame {}: {}", sequence, err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: u32 = 0x1234abcd;
+
+    async fn unlocked(passphrase: &str, session: u32) -> Cipher {
+        let mut cipher = Cipher::new(Some(passphrase.to_string()));
+        cipher.unlock(session).await;
+        return cipher;
+    }
+
+    #[tokio::test]
+    async fn sealed_data_opens_with_the_same_passphrase() {
+        let mut sender = unlocked("correct horse", SESSION).await;
+        let mut receiver = unlocked("correct horse", SESSION).await;
+        let sealed = sender.seal_segment(SESSION, 7, b"some data").unwrap();
+        assert_eq!(sealed.len(), b"some data".len() + TAG_SIZE);
+        assert_eq!(receiver.open_segment(SESSION, 7, &sealed).unwrap(), b"some data");
+        // a segment moved somewhere else doesn't open
+        assert!(receiver.open_segment(SESSION, 8, &sealed).is_err());
+        let symbol = sender.seal_symbol(SESSION, b"\0\0\0\x05symbol").unwrap();
+        assert_eq!(&symbol[0..4], b"\0\0\0\x05");
+        assert_eq!(receiver.open_symbol(SESSION, &symbol).unwrap(), b"\0\0\0\x05symbol");
+        let body = sender.seal_frame(SESSION, 3, 1, b"name").unwrap();
+        assert_eq!(receiver.open_frame(SESSION, 3, 1, &body).unwrap(), b"name");
+        assert!(receiver.open_frame(SESSION, 4, 1, &body).is_err());
+    }
+
+    #[tokio::test]
+    async fn the_wrong_passphrase_or_session_fails() {
+        let mut sender = unlocked("correct horse", SESSION).await;
+        let sealed = sender.seal_segment(SESSION, 0, b"some data").unwrap();
+        assert!(unlocked("battery staple", SESSION).await.open_segment(SESSION, 0, &sealed).is_err());
+        assert!(unlocked("correct horse", SESSION + 1).await.open_segment(SESSION + 1, 0, &sealed).is_err());
+        assert!(Cipher::new(None).open_segment(SESSION, 0, &sealed).is_err());
+    }
+
+    #[test]
+    fn nothing_is_sealed_before_unlocking() {
+        let mut cipher = Cipher::new(Some("correct horse".to_string()));
+        assert!(cipher.seal_segment(SESSION, 0, b"some data").is_err());
+        assert!(cipher.open_segment(SESSION, 0, b"some data and a tag").is_err());
+        assert_eq!(Cipher::new(None).seal_segment(SESSION, 0, b"some data").unwrap(), b"some data");
+    }
+}
@@ -1,70 +1,669 @@
-use image::{DynamicImage, ImageBuffer, Rgb};
-use qrcode::{EcLevel, QrCode};
-use quircs::Quirc;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
 
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Pixel, Rgb, RgbImage};
+use image::imageops::replace;
+use qrcode::EcLevel;
+
+use crate::capacity::Capacity;
+use crate::crosstalk::Crosstalk;
 use crate::Frame;
+use crate::frame::FRAME_VERSION_1;
+use crate::graphics::{self, CELL_HEIGHT, CELL_WIDTH};
 use crate::log::Log;
+use crate::symbology::{CodeKind, Symbol, Symbology};
+
+/// Indexed by which of the top left, top right, bottom left and bottom right are light
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+///
+/// How modules are drawn with characters.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rendering {
+    /// Each module is a block of characters, as big as asked
+    #[default]
+    Block,
+    /// Two modules to a character, one above the other
+    Half,
+    /// Four modules to a character, two by two
+    Quadrant,
+    /// A bitmap drawn with sixels
+    Sixel,
+    /// A bitmap drawn with kitty's graphics protocol
+    Kitty,
+    /// Whichever bitmap the terminal can draw, or blocks if it can't
+    Bitmap,
+}
+
+impl Rendering {
+    ///
+    /// How many characters across and down a code `modules` wide takes.  Blocks are
+    /// `width` by `height` characters a module, and bitmaps go in `cell` pixels a character.
+    ///
+    pub fn size(&self, modules: u32, width: u32, height: u32, cell: (u32, u32)) -> (u32, u32) {
+        return match self {
+            Rendering::Block => (modules * width, modules * height),
+            Rendering::Half => (modules, (modules + 1) / 2),
+            Rendering::Quadrant => ((modules + 1) / 2, (modules + 1) / 2),
+            // bitmap modules are square, `width` pixels a side
+            Rendering::Sixel | Rendering::Kitty | Rendering::Bitmap => {
+                let pixels = modules * width;
+                ((pixels + cell.0 - 1) / cell.0, (pixels + cell.1 - 1) / cell.1)
+            }
+        };
+    }
+
+    pub fn is_bitmap(&self) -> bool {
+        return matches!(self, Rendering::Sixel | Rendering::Kitty | Rendering::Bitmap);
+    }
+
+    ///
+    /// What `Bitmap` comes to in this terminal.
+    ///
+    pub fn resolve(self) -> Self {
+        return match self {
+            Rendering::Bitmap => graphics::detect().unwrap_or(Rendering::Block),
+            rendering => rendering,
+        };
+    }
+
+    ///
+    /// How many modules across and down go in a character.
+    ///
+    fn cell(&self) -> (usize, usize) {
+        return match self {
+            Rendering::Half => (1, 2),
+            Rendering::Quadrant => (2, 2),
+            _ => (1, 1),
+        };
+    }
+}
+
+impl Display for Rendering {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        return f.write_str(match self {
+            Rendering::Block => "block",
+            Rendering::Half => "half",
+            Rendering::Quadrant => "quadrant",
+            Rendering::Sixel => "sixel",
+            Rendering::Kitty => "kitty",
+            Rendering::Bitmap => "bitmap",
+        });
+    }
+}
+
+impl FromStr for Rendering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "block" => Ok(Rendering::Block),
+            "half" => Ok(Rendering::Half),
+            "quadrant" => Ok(Rendering::Quadrant),
+            "sixel" => Ok(Rendering::Sixel),
+            "kitty" => Ok(Rendering::Kitty),
+            "bitmap" => Ok(Rendering::Bitmap),
+            _ => Err(format!("{} isn't one of block, half, quadrant, sixel, kitty or bitmap", s)),
+        };
+    }
+}
+
+///
+/// How much of a code can be lost and still be read: L, M, Q or H.
+///
+pub fn parse_ec_level(level: &str) -> Result<EcLevel, String> {
+    return match level.to_ascii_uppercase().as_str() {
+        "L" => Ok(EcLevel::L),
+        "M" => Ok(EcLevel::M),
+        "Q" => Ok(EcLevel::Q),
+        "H" => Ok(EcLevel::H),
+        _ => Err(format!("{} isn't one of L, M, Q or H", level)),
+    };
+}
 
 pub struct Encoder {
     width: u32,
     height: u32,
-    quiet_zone: bool
+    quiet_zone: bool,
+    ec_level: EcLevel,
+    rendering: Rendering,
+    symbology: Box<dyn Symbology>,
+    /// Every code is big enough for this so it stays the same size on screen
+    fixed_payload: Option<usize>,
+    /// The size of code that holds the fixed payload
+    version: Option<i16>,
+    /// How many codes go side by side when there are several
+    across: usize,
+    /// Codes may be drawn in colour, so they're written straight to the terminal
+    rgb: bool,
+    /// Up to three frames go in each code, one in each of red, green and blue
+    colour: bool,
+    /// How many pixels across and down a character is, for sizing bitmaps
+    cell: (u32, u32),
 }
 
 impl Encoder {
-    pub fn new(width: u32, height: u32, quiet_zone: bool) -> Self {
-        return Self {
+    pub fn new(width: u32, height: u32, quiet_zone: bool, ec_level: EcLevel, rendering: Rendering, rgb: bool, symbology: CodeKind) -> Result<Self, String> {
+        return Ok(Self {
             width,
             height,
-            quiet_zone
-        }
+            quiet_zone,
+            ec_level,
+            rendering,
+            symbology: symbology.symbology()?,
+            fixed_payload: None,
+            version: None,
+            across: 1,
+            rgb,
+            colour: rgb,
+            cell: (CELL_WIDTH, CELL_HEIGHT),
+        });
+    }
+
+    ///
+    /// Make every code big enough for `fixed_payload`, and put `across` of them side by side.
+    ///
+    pub fn lay_out(&mut self, fixed_payload: Option<usize>, across: usize) {
+        self.fixed_payload = fixed_payload;
+        self.version = fixed_payload
+            .and_then(|payload| Capacity::holding(self.symbology.as_ref(), payload, self.ec_level))
+            .map(|capacity| capacity.version);
+        self.across = across.max(1);
+    }
+
+    ///
+    /// Show another kind of code from now on, as big as the last if they were fixed.
+    ///
+    pub fn switch(&mut self, symbology: CodeKind) -> Result<(), String> {
+        self.symbology = symbology.symbology()?;
+        self.lay_out(self.fixed_payload, self.across);
+        return Ok(());
+    }
+
+    ///
+    /// Size bitmaps for characters this many pixels across and down.
+    ///
+    pub fn measure(&mut self, cell: (u32, u32)) {
+        self.cell = cell;
+    }
+
+    ///
+    /// Lay frames over each other in colour from now on, if we can, or go back to one frame
+    /// to a code.
+    ///
+    pub fn colour(&mut self, colour: bool) {
+        self.colour = colour && self.rgb;
+    }
+
+    pub fn ec_level(&self) -> EcLevel {
+        return self.ec_level;
+    }
+
+    pub fn symbology(&self) -> &dyn Symbology {
+        return self.symbology.as_ref();
+    }
+
+    ///
+    /// Whether codes are escapes that go straight to the terminal rather than text for tui.
+    ///
+    pub fn is_direct(&self) -> bool {
+        return self.rgb || self.rendering.is_bitmap();
+    }
+
+    ///
+    /// How many characters across and down a code this version takes.
+    ///
+    pub fn size(&self, version: i16) -> (u32, u32) {
+        return self.rendering.size(self.symbology.modules(version) + 2 * self.quiet_zone(), self.width, self.height, self.cell);
     }
+
     pub fn encode(&self, frame: &Frame) -> String {
-        let code = QrCode::with_error_correction_level(frame, EcLevel::L)
-            .expect("Failed to generate qrcode!");
-        return code.render()
-            .quiet_zone(self.quiet_zone)
-            .module_dimensions(self.width, self.height)
-            .light_color('█')
-            .dark_color(' ')
-            .build();
+        return self.draw(&self.code(frame));
+    }
+
+    ///
+    /// Several codes in a grid, one for each lane.  In colour each code carries up to three
+    /// frames.
+    ///
+    pub fn encode_lanes(&self, frames: &[Frame]) -> String {
+        let per_code = if self.colour { 3 } else { 1 };
+        let codes: Vec<Vec<Symbol>> = frames.chunks(per_code).map(|frames| self.codes(frames)).collect();
+        match (self.rendering, self.colour) {
+            (Rendering::Kitty, false) => return graphics::kitty(&self.tile(codes.iter().map(|codes| self.rasterise(&codes[0])).collect())),
+            (Rendering::Sixel, false) => return graphics::sixel(&self.tile(codes.iter().map(|codes| self.rasterise(&codes[0])).collect())),
+            (Rendering::Kitty, true) => return graphics::kitty_rgb(&self.tile(codes.iter().map(|codes| self.rasterise_rgb(codes)).collect())),
+            (Rendering::Sixel, true) => return graphics::sixel_rgb(&self.tile(codes.iter().map(|codes| self.rasterise_rgb(codes)).collect())),
+            _ => {}
+        }
+        let tiles: Vec<Vec<String>> = codes.iter()
+            .map(|codes| self.draw_codes(codes).lines().map(String::from).collect())
+            .collect();
+        let mut lines = Vec::new();
+        for row in tiles.chunks(self.across) {
+            if !lines.is_empty() {
+                lines.push(String::new());
+            }
+            let height = row.iter().map(|tile| tile.len()).max().unwrap_or(0);
+            for y in 0..height {
+                let line: Vec<String> = row.iter()
+                    .map(|tile| {
+                        let width = tile.first().map_or(0, |line| visible_width(line));
+                        let line = tile.get(y).map_or("", |line| line.as_str());
+                        format!("{}{}", line, " ".repeat(width - visible_width(line)))
+                    })
+                    .collect();
+                lines.push(line.join(" "));
+            }
+        }
+        return lines.join("\n");
+    }
+
+    ///
+    /// Images in rows of `across`, each in a cell as big as the biggest.
+    ///
+    fn tile<P: Pixel + 'static>(&self, images: Vec<ImageBuffer<P, Vec<P::Subpixel>>>) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let width = images.iter().map(|image| image.width()).max().unwrap_or(0);
+        let height = images.iter().map(|image| image.height()).max().unwrap_or(0);
+        let rows = (images.len() + self.across - 1) / self.across;
+        let mut tiled = ImageBuffer::new(width * self.across.min(images.len()) as u32, height * rows as u32);
+        for (i, image) in images.iter().enumerate() {
+            replace(&mut tiled, image, (i % self.across) as u32 * width, (i / self.across) as u32 * height);
+        }
+        return tiled;
+    }
+
+    ///
+    /// A code for each frame, all the same size when the kind lets us pick so they can be laid
+    /// over each other.
+    ///
+    fn codes(&self, frames: &[Frame]) -> Vec<Symbol> {
+        let codes: Vec<Symbol> = frames.iter().map(|frame| self.code(frame)).collect();
+        let version = match codes.iter().map(|code| code.version).max() {
+            Some(version) => version,
+            None => return codes,
+        };
+        return frames.iter().zip(codes)
+            .map(|(frame, code)| if code.version == version {
+                code
+            } else {
+                self.symbology.encode(frame.as_ref(), Some(version), self.ec_level).expect("Failed to generate code!")
+            })
+            .collect();
+    }
+
+    fn code(&self, frame: &Frame) -> Symbol {
+        // a frame too big for the fixed version still gets shown, just bigger
+        return self.symbology.encode(frame.as_ref(), self.version, self.ec_level).expect("Failed to generate code!");
+    }
+
+    fn quiet_zone(&self) -> u32 {
+        return if self.quiet_zone { self.symbology.quiet_zone() } else { 0 };
+    }
+
+    ///
+    /// Whether each module is light, with the quiet zone around it.
+    ///
+    fn framed(&self, code: &Symbol) -> (usize, Vec<bool>) {
+        let quiet_zone = self.quiet_zone() as usize;
+        let size = code.width + 2 * quiet_zone;
+        let mut light = vec![true; size * size];
+        for y in 0..code.width {
+            for x in 0..code.width {
+                light[(y + quiet_zone) * size + x + quiet_zone] = code.is_light(x, y);
+            }
+        }
+        return (size, light);
+    }
+
+    fn draw(&self, code: &Symbol) -> String {
+        return match self.rendering {
+            Rendering::Half | Rendering::Quadrant => self.render_cells(code),
+            Rendering::Sixel => graphics::sixel(&self.rasterise(code)),
+            Rendering::Kitty => graphics::kitty(&self.rasterise(code)),
+            // a bitmap nobody resolved gets blocks
+            Rendering::Block | Rendering::Bitmap => self.render_blocks(code),
+        };
+    }
+
+    fn draw_codes(&self, codes: &[Symbol]) -> String {
+        if !self.colour {
+            return self.draw(&codes[0]);
+        }
+        return match self.rendering {
+            Rendering::Sixel => graphics::sixel_rgb(&self.rasterise_rgb(codes)),
+            Rendering::Kitty => graphics::kitty_rgb(&self.rasterise_rgb(codes)),
+            _ => self.render_rgb(codes),
+        };
+    }
+
+    fn rasterise(&self, code: &Symbol) -> GrayImage {
+        let (size, light) = self.framed(code);
+        return GrayImage::from_fn(size as u32 * self.width, size as u32 * self.width, |x, y| {
+            return Luma([if light[(y / self.width) as usize * size + (x / self.width) as usize] { 255 } else { 0 }]);
+        });
+    }
+
+    fn rasterise_rgb(&self, codes: &[Symbol]) -> RgbImage {
+        let (size, masks) = self.composite(codes);
+        return RgbImage::from_fn(size as u32 * self.width, size as u32 * self.width, |x, y| {
+            return Rgb(channels(masks[(y / self.width) as usize * size + (x / self.width) as usize]));
+        });
+    }
+
+    ///
+    /// The codes laid over each other, with a bit for each of red, green and blue that's set
+    /// where that code is light.  Channels without a code of their own repeat one that has,
+    /// turned a quarter for each time it's been used so no two channels are alike and the
+    /// crosstalk can still be told apart.  Smaller codes sit in the middle of bigger ones.
+    ///
+    fn composite(&self, codes: &[Symbol]) -> (usize, Vec<u8>) {
+        let quiet_zone = self.quiet_zone() as usize;
+        let width = codes.iter().map(|code| code.width).max().unwrap_or(0);
+        let size = width + 2 * quiet_zone;
+        // the quiet zone is light in all of them
+        let mut masks = vec![0b111; size * size];
+        for channel in 0..3 {
+            let code = &codes[channel % codes.len()];
+            let offset = quiet_zone + (width - code.width) / 2;
+            for y in 0..code.width {
+                for x in 0..code.width {
+                    if !code.is_light(x, y) {
+                        let (x, y) = turned(x, y, code.width, channel / codes.len());
+                        masks[(y + offset) * size + x + offset] &= !(1 << channel);
+                    }
+                }
+            }
+        }
+        return (size, masks);
+    }
+
+    ///
+    /// Each module is a block of characters, light ones drawn in the foreground colour and
+    /// dark ones left as the background.
+    ///
+    fn render_blocks(&self, code: &Symbol) -> String {
+        let (size, light) = self.framed(code);
+        let (on, off) = ("█".repeat(self.width as usize), " ".repeat(self.width as usize));
+        let mut lines = Vec::new();
+        for row in light.chunks(size) {
+            let line: String = row.iter().map(|light| if *light { on.as_str() } else { off.as_str() }).collect();
+            for _ in 0..self.height {
+                lines.push(line.clone());
+            }
+        }
+        return lines.join("\n");
+    }
+
+    ///
+    /// The codes laid over each other in truecolor.  Blocks are drawn in the colour of the
+    /// module, halves in the colour of the top module on the colour of the bottom one.
+    ///
+    fn render_rgb(&self, codes: &[Symbol]) -> String {
+        let (size, masks) = self.composite(codes);
+        // past the edge is dark
+        let color = |x: usize, y: usize| {
+            let [r, g, b] = channels(if y < size { masks[y * size + x] } else { 0 });
+            return format!("{};{};{}", r, g, b);
+        };
+        let mut lines = Vec::new();
+        if self.rendering == Rendering::Half {
+            for y in (0..size).step_by(2) {
+                let line: String = (0..size)
+                    .map(|x| format!("\x1b[38;2;{};48;2;{}m▀", color(x, y), color(x, y + 1)))
+                    .collect();
+                lines.push(line + "\x1b[0m");
+            }
+        } else {
+            let block = "█".repeat(self.width as usize);
+            for y in 0..size {
+                let line: String = (0..size)
+                    .map(|x| format!("\x1b[38;2;{}m{}", color(x, y), block))
+                    .collect();
+                for _ in 0..self.height {
+                    lines.push(line.clone() + "\x1b[0m");
+                }
+            }
+        }
+        return lines.join("\n");
+    }
+
+    ///
+    /// Several modules to a character.  Like blocks, light modules are drawn in the foreground
+    /// colour and dark ones are left as the background.
+    ///
+    fn render_cells(&self, code: &Symbol) -> String {
+        let (size, light) = self.framed(code);
+        // past the edge is background
+        let light = |x: usize, y: usize| x < size && y < size && light[y * size + x];
+        let (across, down) = self.rendering.cell();
+        let mut rendered = String::new();
+        for y in (0..size).step_by(down) {
+            if y > 0 {
+                rendered.push('\n');
+            }
+            for x in (0..size).step_by(across) {
+                let mut quadrant = 0;
+                for (bit, (dx, dy)) in [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter().enumerate() {
+                    // a module as wide as the character fills both of its quadrants
+                    if light(x + dx.min(across - 1), y + dy.min(down - 1)) {
+                        quadrant |= 1 << bit;
+                    }
+                }
+                rendered.push(QUADRANTS[quadrant]);
+            }
+        }
+        return rendered;
+    }
+}
+
+///
+/// Full red, green and blue for the bits of a composite module.
+///
+fn channels(mask: u8) -> [u8; 3] {
+    return [0, 1, 2].map(|channel| if mask & (1 << channel) != 0 { 255 } else { 0 });
+}
+
+///
+/// Where a module of a code `width` modules across ends up once the code is turned
+/// clockwise a quarter `turns` times.
+///
+fn turned(x: usize, y: usize, width: usize, turns: usize) -> (usize, usize) {
+    return (0..turns % 4).fold((x, y), |(x, y), _| (width - 1 - y, x));
+}
+
+///
+/// How many columns a line takes once its colour escapes are left out.
+///
+fn visible_width(line: &str) -> usize {
+    let mut width = 0;
+    let mut escape = false;
+    for c in line.chars() {
+        if escape {
+            escape = c != 'm';
+        } else if c == '\x1b' {
+            escape = true;
+        } else {
+            width += 1;
+        }
     }
+    return width;
 }
 
 pub struct Decoder {
     log: Log,
-    decoder: Quirc
+    /// Every kind of code we can read
+    symbologies: Vec<Box<dyn Symbology>>,
+    /// Codes may be laid over each other in red, green and blue
+    rgb: bool,
+    /// Codes without the magic may be v1 frames
+    v1: bool,
 }
 impl Decoder {
-    pub fn new(log: Log) -> Self {
+    pub fn new(log: Log, rgb: bool, v1: bool) -> Self {
         return Self {
-            decoder: Quirc::default(),
+            symbologies: CodeKind::available().iter().filter_map(|kind| kind.symbology().ok()).collect(),
             log,
+            rgb,
+            v1,
         }
     }
 
     pub fn decode(&mut self, image: ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<Frame> {
-        let dyn_image = DynamicImage::ImageRgb8(image.into());
-        let gray_image = dyn_image.into_luma8();
-        let vec = self.decoder.identify(gray_image.width() as usize, gray_image.height() as usize, &gray_image);
+        let crosstalk = if self.rgb { Crosstalk::calibrate(&image) } else { None };
+        let gray_images = match crosstalk {
+            Some(crosstalk) => crosstalk.unmix(&image).to_vec(),
+            // grey codes, like the ones we show back, read as they are
+            None => {
+                let dyn_image = DynamicImage::ImageRgb8(image.into());
+                vec![dyn_image.into_luma8()]
+            }
+        };
         let mut result = Vec::new();
-        for x in vec {
-            match x {
-                Ok(data) => {
-                    match data.decode() {
-                        Ok(data) => {
-                            result.push(Frame::new(data.payload));
+        // a channel with no code of its own repeats one that has
+        let mut seen = Vec::new();
+        for gray_image in gray_images {
+            for symbology in self.symbologies.iter_mut() {
+                for payload in symbology.decode(&gray_image) {
+                    match payload {
+                        Ok(payload) if seen.contains(&payload) => {}
+                        Ok(payload) => {
+                            seen.push(payload.clone());
+                            match Frame::decode(payload, self.v1) {
+                                Ok(frame) => {
+                                    if frame.get_version() == FRAME_VERSION_1 {
+                                        self.log.log("Peer is using v1 frames".to_string());
+                                    }
+                                    result.push(frame);
+                                }
+                                Err(err) => {
+                                    self.log.log(format!("Dropping frame: {}", err));
+                                }
+                            }
                         }
                         Err(err) => {
-                            self.log.log(format!("{:?}", err));
+                            self.log.log(err);
                         }
                     }
                 }
-                Err(err) => {
-                    self.log.log(format!("{:?}", err));
-                }
             }
         }
         return result;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+
+    ///
+    /// What a camera whose channels each pick up some of the others might see.
+    ///
+    fn filmed(image: &RgbImage) -> RgbImage {
+        return RgbImage::from_fn(image.width(), image.height(), |x, y| {
+            let pixel = image.get_pixel(x, y);
+            let channel = |own: usize| {
+                let light = 0.55 * pixel[own] as f32 + 0.3 * pixel[(own + 1) % 3] as f32 + 0.1 * pixel[(own + 2) % 3] as f32;
+                return (30.0 + light + ((x * 7 + y * 13) % 11) as f32) as u8;
+            };
+            return Rgb([channel(0), channel(1), channel(2)]);
+        });
+    }
+
+    fn round_trip(count: usize) {
+        let encoder = Encoder::new(2, 1, true, EcLevel::L, Rendering::Kitty, true, CodeKind::Qr).unwrap();
+        let session = Session::random();
+        let frames: Vec<Frame> = (0..count)
+            .map(|offset| Frame::new_segment(&session, offset, count, vec![offset as u8 * 7; 30 + offset * 40]))
+            .collect();
+        let codes = encoder.codes(&frames);
+        let shown = encoder.rasterise_rgb(&codes);
+        let filmed = filmed(&shown);
+        let crosstalk = Crosstalk::calibrate(&filmed).expect("Channels couldn't be told apart");
+        for (channel, unmixed) in crosstalk.unmix(&filmed).iter().enumerate() {
+            let wrong = shown.pixels().zip(unmixed.pixels())
+                .filter(|(shown, unmixed)| (shown[channel] > 127) != (unmixed[0] > 127))
+                .count();
+            assert_eq!(wrong, 0, "channel {} of {} frames", channel, count);
+        }
+        // the channels with a code of their own show it as it is
+        for (channel, code) in codes.iter().enumerate() {
+            let alone = encoder.rasterise(code);
+            assert!(alone.pixels().zip(shown.pixels()).all(|(alone, shown)| alone[0] == shown[channel]));
+        }
+    }
+
+    #[test]
+    fn one_frame_unmixes() {
+        round_trip(1);
+    }
+
+    #[test]
+    fn two_frames_unmix() {
+        round_trip(2);
+    }
+
+    #[test]
+    fn three_frames_unmix() {
+        round_trip(3);
+    }
+
+    #[test]
+    fn grey_codes_are_not_unmixed() {
+        let encoder = Encoder::new(2, 1, true, EcLevel::L, Rendering::Kitty, false, CodeKind::Qr).unwrap();
+        let code = encoder.code(&Frame::new_cts(&Session::random(), 0, &[]));
+        let grey = DynamicImage::ImageLuma8(encoder.rasterise(&code)).into_rgb8();
+        assert!(Crosstalk::calibrate(&grey).is_none());
+    }
+
+    ///
+    /// Which of a character's quadrants should be light for the modules it covers.
+    ///
+    fn expected_cells(code: &Symbol, across: usize, down: usize) -> Vec<Vec<char>> {
+        let light = |x: usize, y: usize| x < code.width && y < code.width && code.is_light(x, y);
+        return (0..code.width).step_by(down)
+            .map(|y| (0..code.width).step_by(across)
+                .map(|x| {
+                    let quadrant = [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter().enumerate()
+                        .filter(|(_, (dx, dy))| light(x + (*dx).min(across - 1), y + (*dy).min(down - 1)))
+                        .fold(0, |quadrant, (bit, _)| quadrant | 1 << bit);
+                    return QUADRANTS[quadrant];
+                })
+                .collect())
+            .collect();
+    }
+
+    #[test]
+    fn half_blocks_stack_two_modules_in_a_character() {
+        let encoder = Encoder::new(1, 1, false, EcLevel::L, Rendering::Half, false, CodeKind::Qr).unwrap();
+        let code = encoder.code(&Frame::new_cts(&Session::random(), 0, &[]));
+        let rendered: Vec<Vec<char>> = encoder.render_cells(&code).lines().map(|line| line.chars().collect()).collect();
+        assert_eq!(rendered.len(), (code.width + 1) / 2);
+        assert!(rendered.iter().flatten().all(|glyph| [' ', '▀', '▄', '█'].contains(glyph)));
+        assert_eq!(rendered, expected_cells(&code, 1, 2));
+        // QR codes are an odd number of modules, the last row has nothing below it
+        assert!(rendered.last().unwrap().iter().all(|glyph| [' ', '▀'].contains(glyph)));
+    }
+
+    #[test]
+    fn quadrants_put_four_modules_in_a_character() {
+        let encoder = Encoder::new(1, 1, false, EcLevel::L, Rendering::Quadrant, false, CodeKind::Qr).unwrap();
+        let code = encoder.code(&Frame::new_cts(&Session::random(), 0, &[]));
+        let rendered: Vec<Vec<char>> = encoder.render_cells(&code).lines().map(|line| line.chars().collect()).collect();
+        assert_eq!((rendered.len(), rendered[0].len()), ((code.width + 1) / 2, (code.width + 1) / 2));
+        assert_eq!(rendered, expected_cells(&code, 2, 2));
+        // the finder pattern's dark corner and light ring, top left
+        assert_eq!(rendered[0][0], '▗');
+        assert_eq!(encoder.size(code.version), (rendered[0].len() as u32, rendered.len() as u32));
+    }
+
+    #[test]
+    fn turning_four_times_goes_all_the_way_round() {
+        assert_eq!(turned(1, 0, 5, 1), (4, 1));
+        assert_eq!(turned(1, 0, 5, 2), (3, 4));
+        assert_eq!(turned(1, 0, 5, 4), (1, 0));
+    }
+}
@@ -0,0 +1,458 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::mem::take;
+use std::sync::{Arc, Mutex};
+
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+
+use crate::handshake::Compression;
+use crate::hash::{ContentHasher, Hash};
+use crate::metadata::FileMetadata;
+use crate::transport::SegmentSource;
+
+/// How much of the input is tried before deciding whether compressing it is worth it
+const SAMPLE_SIZE: usize = 16 * 1024;
+/// How much of the input is read at a time
+const CHUNK_SIZE: usize = 4096;
+/// Every byte costs a lot more to show than to squeeze
+const ZSTD_LEVEL: i32 = 19;
+/// How much compressed output is kept behind the furthest read, for segments sent again
+const RETAINED: usize = 1024 * 1024;
+/// How much compressed data is decompressed at a time, a few MiB at most even for zstd
+const FEED_SIZE: usize = 64;
+
+enum Compressor {
+    Store,
+    Deflate(DeflateEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Compressor {
+    fn new(compression: Compression) -> Result<Self> {
+        return Ok(match compression {
+            Compression::None => Compressor::Store,
+            Compression::Deflate => Compressor::Deflate(DeflateEncoder::new(Vec::new(), flate2::Compression::best())),
+            Compression::Zstd => Compressor::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+        });
+    }
+
+    ///
+    /// Compress some more, returning whatever output is ready.
+    ///
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        return match self {
+            Compressor::Store => Ok(data.to_vec()),
+            Compressor::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                Ok(take(encoder.get_mut()))
+            }
+            Compressor::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                Ok(take(encoder.get_mut()))
+            }
+        };
+    }
+
+    fn finish(self) -> Result<Vec<u8>> {
+        return match self {
+            Compressor::Store => Ok(Vec::new()),
+            Compressor::Deflate(encoder) => encoder.finish(),
+            Compressor::Zstd(encoder) => encoder.finish(),
+        };
+    }
+}
+
+///
+/// Whether compressing `sample` saves at least a tenth of it.
+///
+fn is_worthwhile(compression: Compression, sample: &[u8]) -> Result<bool> {
+    let mut compressor = Compressor::new(compression)?;
+    let mut compressed = compressor.compress(sample)?;
+    compressed.extend(compressor.finish()?);
+    return Ok(compressed.len() * 10 < sample.len() * 9);
+}
+
+///
+/// A source compressed as a whole before it's cut into segments.  Only the output near the
+/// furthest read is kept for a file; reading further back compresses it again from the
+/// start.  A pipe can't be read again so everything compressed from one is kept.  Until a
+/// compression is chosen, and when there's no point compressing a file, it reads straight
+/// through to the source.
+///
+pub struct CompressedSource<S: SegmentSource> {
+    input: S,
+    compression: Compression,
+    direct: bool,
+    compressor: Option<Compressor>,
+    output: Vec<u8>,
+    /// Where `output` starts in the compressed stream
+    output_start: usize,
+    /// How long the compressed stream is, once it has all been compressed
+    total: Option<usize>,
+    next_input: usize,
+    read: u64,
+    hasher: ContentHasher,
+    hash: Option<Hash>,
+}
+
+impl<S: SegmentSource> CompressedSource<S> {
+    pub fn new(input: S) -> Self {
+        return Self {
+            input,
+            compression: Compression::None,
+            direct: true,
+            compressor: None,
+            output: Vec::new(),
+            output_start: 0,
+            total: None,
+            next_input: 0,
+            read: 0,
+            hasher: ContentHasher::new(),
+            hash: None,
+        };
+    }
+
+    ///
+    /// Start compressing, unless a sample from the start of the source shows it isn't worth
+    /// it.  Returns the compression actually used.  Must be called before anything is read.
+    ///
+    pub fn choose(&mut self, compression: Compression) -> Result<Compression> {
+        if compression == Compression::None {
+            return Ok(compression);
+        }
+        let mut sample = Vec::new();
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        while sample.len() < SAMPLE_SIZE {
+            let size = self.input.read_segment(self.next_input, &mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            sample.extend_from_slice(&chunk[0..size]);
+            self.next_input += 1;
+        }
+        if !is_worthwhile(compression, &sample)? {
+            // what's been sampled can't be read again from a pipe so that has to be stored
+            if self.input.size().is_some() {
+                return Ok(Compression::None);
+            }
+        } else {
+            self.compression = compression;
+        }
+        let mut compressor = Compressor::new(self.compression)?;
+        // fed a chunk at a time, as it will be if it's ever compressed again
+        for chunk in sample.chunks(CHUNK_SIZE) {
+            self.output.extend(compressor.compress(chunk)?);
+        }
+        self.hasher.update(&sample);
+        self.read = sample.len() as u64;
+        self.compressor = Some(compressor);
+        self.direct = false;
+        return Ok(self.compression);
+    }
+
+    ///
+    /// Go back to the start of the source to get at output that's been let go.
+    ///
+    fn restart(&mut self) -> Result<()> {
+        self.compressor = Some(Compressor::new(self.compression)?);
+        self.output.clear();
+        self.output_start = 0;
+        self.next_input = 0;
+        self.read = 0;
+        self.hasher = ContentHasher::new();
+        return Ok(());
+    }
+
+    ///
+    /// The hash of the uncompressed source once it has all been read.  Sources read
+    /// directly don't keep one.
+    ///
+    pub fn content_hash(&self) -> Option<Hash> {
+        return self.hash;
+    }
+
+    fn compress_more(&mut self, chunk: &mut [u8]) -> Result<()> {
+        let size = self.input.read_segment(self.next_input, chunk)?;
+        if size == 0 {
+            if let Some(compressor) = self.compressor.take() {
+                self.output.extend(compressor.finish()?);
+                self.hash = Some(self.hasher.finish());
+                self.total = Some(self.output_start + self.output.len());
+            }
+            return Ok(());
+        }
+        if let Some(compressor) = &mut self.compressor {
+            self.output.extend(compressor.compress(&chunk[0..size])?);
+        }
+        self.hasher.update(&chunk[0..size]);
+        self.read += size as u64;
+        self.next_input += 1;
+        return Ok(());
+    }
+}
+
+impl<S: SegmentSource> SegmentSource for CompressedSource<S> {
+    fn size(&self) -> Option<usize> {
+        return if self.compression == Compression::None {
+            self.input.size()
+        } else {
+            self.total
+        };
+    }
+
+    fn metadata(&self) -> FileMetadata {
+        let metadata = self.input.metadata();
+        return FileMetadata {
+            // a pipe's size is only known once it has been read to the end
+            size: if self.hash.is_some() { Some(self.read) } else { metadata.size },
+            compression: self.compression,
+            ..metadata
+        };
+    }
+
+    fn read_segment(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.direct {
+            return self.input.read_segment(offset, buf);
+        }
+        let start = offset * buf.len();
+        let end = start + buf.len();
+        if start < self.output_start {
+            self.restart()?;
+        }
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        while self.output_start + self.output.len() < end && self.compressor.is_some() {
+            self.compress_more(&mut chunk)?;
+        }
+        let available = self.output_start + self.output.len();
+        if start >= available {
+            return Ok(0);
+        }
+        let size = end.min(available) - start;
+        let from = start - self.output_start;
+        buf[0..size].copy_from_slice(&self.output[from..from + size]);
+        // a file can be compressed again, so let go of what's well behind us
+        if self.input.size().is_some() && from > 2 * RETAINED {
+            self.output.drain(0..from - RETAINED);
+            self.output_start += from - RETAINED;
+        }
+        return Ok(size);
+    }
+}
+
+///
+/// A `CompressedSource` that does its reading and compressing on tokio's blocking threads.
+/// Squeezing a sample out of zstd at level 19, or compressing a file again from the start,
+/// would otherwise hold up everything else on the runtime.
+///
+pub struct BlockingSource<S: SegmentSource> {
+    input: Arc<Mutex<CompressedSource<S>>>,
+}
+
+impl<S: SegmentSource + 'static> BlockingSource<S> {
+    pub fn new(input: CompressedSource<S>) -> Self {
+        return Self {
+            input: Arc::new(Mutex::new(input)),
+        };
+    }
+
+    pub async fn choose(&self, compression: Compression) -> Result<Compression> {
+        let input = self.input.clone();
+        return tokio::task::spawn_blocking(move || input.lock().unwrap().choose(compression)).await.unwrap();
+    }
+
+    ///
+    /// Read up to `size` bytes of segment `offset`, nothing past the end.
+    ///
+    pub async fn read_segment(&self, offset: usize, size: usize) -> Result<Vec<u8>> {
+        let input = self.input.clone();
+        return tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0u8; size];
+            let size = input.lock().unwrap().read_segment(offset, &mut buf)?;
+            buf.truncate(size);
+            Ok(buf)
+        }).await.unwrap();
+    }
+
+    pub fn size(&self) -> Option<usize> {
+        return self.input.lock().unwrap().size();
+    }
+
+    pub fn metadata(&self) -> FileMetadata {
+        return self.input.lock().unwrap().metadata();
+    }
+
+    pub fn content_hash(&self) -> Option<Hash> {
+        return self.input.lock().unwrap().content_hash();
+    }
+}
+
+///
+/// Undoes a `CompressedSource` as the data arrives, in order, and no further than the size
+/// the sender gave.  The data is fed in a little at a time so a segment that blows up to
+/// far more than that is caught before it's all in memory.
+///
+pub struct Decompressor {
+    decoder: Decoder,
+    limit: Option<u64>,
+    produced: u64,
+}
+
+enum Decoder {
+    Deflate(DeflateDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl Decompressor {
+    pub fn new(compression: Compression, limit: Option<u64>) -> Result<Option<Self>> {
+        let decoder = match compression {
+            Compression::None => return Ok(None),
+            Compression::Deflate => Decoder::Deflate(DeflateDecoder::new(Vec::new())),
+            Compression::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+        };
+        return Ok(Some(Self {
+            decoder,
+            limit,
+            produced: 0,
+        }));
+    }
+
+    ///
+    /// Decompress some more, returning whatever output is ready.
+    ///
+    pub fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for piece in data.chunks(FEED_SIZE) {
+            let more = match &mut self.decoder {
+                Decoder::Deflate(decoder) => {
+                    decoder.write_all(piece)?;
+                    take(decoder.get_mut())
+                }
+                Decoder::Zstd(decoder) => {
+                    decoder.write_all(piece)?;
+                    take(decoder.get_mut())
+                }
+            };
+            self.produce(more.len())?;
+            output.extend(more);
+        }
+        return Ok(output);
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let output = match &mut self.decoder {
+            Decoder::Deflate(decoder) => {
+                decoder.try_finish()?;
+                take(decoder.get_mut())
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.flush()?;
+                take(decoder.get_mut())
+            }
+        };
+        self.produce(output.len())?;
+        return Ok(output);
+    }
+
+    fn produce(&mut self, size: usize) -> Result<()> {
+        self.produced += size as u64;
+        return match self.limit {
+            Some(limit) if self.produced > limit => {
+                Err(Error::new(ErrorKind::InvalidData, format!("The data decompresses to more than the {}b the sender said", limit)))
+            }
+            _ => Ok(()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+
+    ///
+    /// A file's worth of data already in memory.
+    ///
+    struct Memory(Vec<u8>);
+
+    impl SegmentSource for Memory {
+        fn size(&self) -> Option<usize> {
+            return Some(self.0.len());
+        }
+
+        fn read_segment(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let start = (offset * buf.len()).min(self.0.len());
+            let size = buf.len().min(self.0.len() - start);
+            buf[0..size].copy_from_slice(&self.0[start..start + size]);
+            return Ok(size);
+        }
+    }
+
+    fn text() -> Vec<u8> {
+        return (0..5000).flat_map(|i| format!("line {} of something compressible\n", i * 7919 % 100003).into_bytes()).collect();
+    }
+
+    fn read_all<S: SegmentSource>(source: &mut S, segment_size: usize) -> Vec<Vec<u8>> {
+        let mut segments = Vec::new();
+        let mut buf = vec![0u8; segment_size];
+        loop {
+            let size = source.read_segment(segments.len(), &mut buf).unwrap();
+            if size == 0 {
+                return segments;
+            }
+            segments.push(buf[0..size].to_vec());
+        }
+    }
+
+    #[test]
+    fn compressed_segments_decompress_to_the_source() {
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            let data = text();
+            let mut source = CompressedSource::new(Memory(data.clone()));
+            assert_eq!(source.choose(compression).unwrap(), compression);
+            let segments = read_all(&mut source, 100);
+            assert_eq!(source.size(), Some(segments.iter().map(Vec::len).sum()));
+            assert!(source.size().unwrap() < data.len() / 2);
+            let mut hasher = ContentHasher::new();
+            hasher.update(&data);
+            assert_eq!(source.content_hash(), Some(hasher.finish()));
+            let mut decompressor = Decompressor::new(compression, Some(data.len() as u64)).unwrap().unwrap();
+            let mut decompressed = Vec::new();
+            for segment in segments {
+                decompressed.extend(decompressor.decompress(&segment).unwrap());
+            }
+            decompressed.extend(decompressor.finish().unwrap());
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[test]
+    fn segments_read_after_a_restart_are_the_same() {
+        let mut source = CompressedSource::new(Memory(text()));
+        source.choose(Compression::Zstd).unwrap();
+        let segments = read_all(&mut source, 64);
+        source.restart().unwrap();
+        let mut buf = vec![0u8; 64];
+        let size = source.read_segment(3, &mut buf).unwrap();
+        assert_eq!(&buf[0..size], &segments[3][..]);
+        source.restart().unwrap();
+        assert_eq!(read_all(&mut source, 64), segments);
+    }
+
+    #[test]
+    fn data_that_doesnt_compress_is_read_as_is() {
+        let mut data = vec![0u8; 50000];
+        rand::thread_rng().fill_bytes(&mut data);
+        let mut source = CompressedSource::new(Memory(data.clone()));
+        assert_eq!(source.choose(Compression::Zstd).unwrap(), Compression::None);
+        assert_eq!(read_all(&mut source, 1000).concat(), data);
+    }
+
+    #[test]
+    fn decompressing_past_the_size_fails() {
+        let mut compressor = Compressor::new(Compression::Zstd).unwrap();
+        let mut compressed = compressor.compress(&vec![0u8; 1 << 20]).unwrap();
+        compressed.extend(compressor.finish().unwrap());
+        let mut decompressor = Decompressor::new(Compression::Zstd, Some(1000)).unwrap().unwrap();
+        assert_eq!(decompressor.decompress(&compressed).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
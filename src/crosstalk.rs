@@ -0,0 +1,170 @@
+use image::{GrayImage, Luma, RgbImage};
+
+/// Only every this many pixels across and down are looked at to calibrate
+const SAMPLE_STEP: u32 = 2;
+/// How far from singular the mixing can get, against no crosstalk at all, before the codes
+/// can't be told apart
+const MIN_SEPARATION: f32 = 0.1;
+/// How many times the codes are guessed and the channels fitted to them
+const FITS: usize = 6;
+/// The fewest samples, as a share of them all, that have to tell two codes apart
+const MIN_PIVOT: f64 = 1e-3;
+
+///
+/// How much of each of the red, green and blue codes a camera sees in each of its channels.
+/// Its filters overlap and the screen's colours aren't the camera's, so each channel comes
+/// back with some of the other codes in it.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Crosstalk {
+    /// Undoes how much each code shows up in each channel
+    unmixing: [[f32; 3]; 3],
+    /// What each channel reads with every code dark
+    dark: [f32; 3],
+}
+
+impl Crosstalk {
+    ///
+    /// Work out the crosstalk from the image itself.  Each code is guessed from its own channel
+    /// and each channel is fitted to how light the codes are guessed to be, then the codes are
+    /// guessed again from what that fit undoes, a few times over.  None when the channels can't
+    /// be told apart, as with a grey code.
+    ///
+    pub fn calibrate(image: &RgbImage) -> Option<Self> {
+        let samples: Vec<[f32; 3]> = image.enumerate_pixels()
+            .filter(|(x, y, _)| x % SAMPLE_STEP == 0 && y % SAMPLE_STEP == 0)
+            .map(|(_, _, pixel)| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+            .collect();
+        let count = samples.len() as f32;
+        let mut mean = [0f32; 3];
+        for sample in &samples {
+            for (mean, value) in mean.iter_mut().zip(sample) {
+                *mean += value / count;
+            }
+        }
+        let mut crosstalk = Self::fit(&samples, |sample| [0, 1, 2].map(|code| sample[code] > mean[code]))?;
+        for _ in 1..FITS {
+            // codes guessed so badly they can't be told apart are no better than the last fit
+            match Self::fit(&samples, |sample| crosstalk.light(sample).map(|light| light > 0.5)) {
+                Some(better) => crosstalk = better,
+                None => break,
+            }
+        }
+        return Some(crosstalk);
+    }
+
+    ///
+    /// Least squares of each channel against what it reads with every code dark, plus how much
+    /// it picks up from each code that's light.
+    ///
+    fn fit<F: Fn(&[f32; 3]) -> [bool; 3]>(samples: &[[f32; 3]], guess: F) -> Option<Self> {
+        let mut normal = [[0f64; 4]; 4];
+        let mut moments = [[0f64; 3]; 4];
+        for sample in samples {
+            let [red, green, blue] = guess(sample);
+            let terms = [1.0, red as u8 as f64, green as u8 as f64, blue as u8 as f64];
+            for (row, term) in normal.iter_mut().zip(terms) {
+                for (sum, other) in row.iter_mut().zip(terms) {
+                    *sum += term * other;
+                }
+            }
+            for (row, term) in moments.iter_mut().zip(terms) {
+                for (sum, value) in row.iter_mut().zip(sample) {
+                    *sum += term * *value as f64;
+                }
+            }
+        }
+        let fitted = solve(normal, moments)?;
+        let mut mixing = [[0f32; 3]; 3];
+        for (code, picked_up) in fitted[1..].iter().enumerate() {
+            for (channel, amount) in picked_up.iter().enumerate() {
+                mixing[channel][code] = *amount as f32;
+            }
+        }
+        return Some(Self {
+            unmixing: invert(mixing)?,
+            dark: fitted[0].map(|dark| dark as f32),
+        });
+    }
+
+    ///
+    /// How light each code is at a pixel, 0 for dark and 1 for light.
+    ///
+    fn light(&self, pixel: &[f32; 3]) -> [f32; 3] {
+        let measured = [0, 1, 2].map(|channel| pixel[channel] - self.dark[channel]);
+        return self.unmixing.map(|unmixing| unmixing.iter().zip(&measured).map(|(weight, value)| weight * value).sum());
+    }
+
+    ///
+    /// Each code on its own, light where it's light whatever the others are doing.
+    ///
+    pub fn unmix(&self, image: &RgbImage) -> [GrayImage; 3] {
+        let (width, height) = image.dimensions();
+        let mut codes = [GrayImage::new(width, height), GrayImage::new(width, height), GrayImage::new(width, height)];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let light = self.light(&[pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]);
+            for (code, light) in codes.iter_mut().zip(light) {
+                code.put_pixel(x, y, Luma([(light * 255.0).clamp(0.0, 255.0) as u8]));
+            }
+        }
+        return codes;
+    }
+}
+
+///
+/// Solve the normal equations by elimination, unless two of the codes were never guessed
+/// apart.
+///
+fn solve(mut normal: [[f64; 4]; 4], mut moments: [[f64; 3]; 4]) -> Option<[[f64; 3]; 4]> {
+    let scale = normal[0][0];
+    for column in 0..4 {
+        let pivot = (column..4).max_by(|a, b| normal[*a][column].abs().total_cmp(&normal[*b][column].abs()))?;
+        if normal[pivot][column].abs() <= scale * MIN_PIVOT {
+            return None;
+        }
+        normal.swap(column, pivot);
+        moments.swap(column, pivot);
+        for row in 0..4 {
+            if row == column {
+                continue;
+            }
+            let factor = normal[row][column] / normal[column][column];
+            let (eliminated, by) = (normal[column], moments[column]);
+            for (value, pivot_value) in normal[row].iter_mut().zip(eliminated) {
+                *value -= factor * pivot_value;
+            }
+            for (value, pivot_value) in moments[row].iter_mut().zip(by) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+    for (row, moments) in moments.iter_mut().enumerate() {
+        for value in moments.iter_mut() {
+            *value /= normal[row][row];
+        }
+    }
+    return Some(moments);
+}
+
+///
+/// The inverse of the mixing, unless it's too near singular to be any use.
+///
+fn invert(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    // taking rows and columns round in a cycle gets the cofactor's sign right
+    let cofactor = |row: usize, column: usize| {
+        let (r1, r2) = ((row + 1) % 3, (row + 2) % 3);
+        let (c1, c2) = ((column + 1) % 3, (column + 2) % 3);
+        return m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1];
+    };
+    let determinant: f32 = (0..3).map(|column| m[0][column] * cofactor(0, column)).sum();
+    if (0..3).any(|i| m[i][i] <= 0.0) || determinant.abs() < MIN_SEPARATION * m[0][0] * m[1][1] * m[2][2] {
+        return None;
+    }
+    let mut inverse = [[0f32; 3]; 3];
+    for (row, inverse) in inverse.iter_mut().enumerate() {
+        for (column, value) in inverse.iter_mut().enumerate() {
+            *value = cofactor(column, row) / determinant;
+        }
+    }
+    return Some(inverse);
+}
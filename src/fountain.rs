@@ -0,0 +1,175 @@
+use std::collections::{HashSet, VecDeque};
+
+use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation};
+
+/// How many repair symbols to generate per source block at a time
+const REPAIR_BATCH: u32 = 16;
+/// The most data RaptorQ can describe, see errata 5548 of RFC 6330
+const MAX_TRANSFER_LENGTH: u64 = 942574504275;
+/// K'max of RFC 6330, the most source symbols in a block
+const MAX_SOURCE_SYMBOLS_PER_BLOCK: u64 = 56403;
+
+///
+/// An endless stream of RaptorQ symbols for some data.  The source symbols go out first,
+/// followed by as many repair symbols as anyone cares to look at.
+///
+pub struct SymbolStream {
+    encoder: Encoder,
+    queue: VecDeque<EncodingPacket>,
+    next_repair: u32,
+}
+
+impl SymbolStream {
+    pub fn new(data: &[u8], symbol_size: u16) -> Self {
+        let encoder = Encoder::with_defaults(data, symbol_size);
+        let queue = encoder.get_block_encoders().iter()
+            .flat_map(|block| block.source_packets())
+            .collect();
+        return Self {
+            encoder,
+            queue,
+            next_repair: 0,
+        }
+    }
+
+    pub fn get_config(&self) -> [u8; 12] {
+        return self.encoder.get_config().serialize();
+    }
+
+    pub fn next_symbol(&mut self) -> Vec<u8> {
+        if self.queue.is_empty() {
+            for block in self.encoder.get_block_encoders() {
+                self.queue.extend(block.repair_packets(self.next_repair, REPAIR_BATCH));
+            }
+            self.next_repair += REPAIR_BATCH;
+        }
+        return self.queue.pop_front().unwrap().serialize();
+    }
+}
+
+///
+/// Collects symbols in whatever order they show up until the data can be rebuilt.
+///
+pub struct SymbolCollector {
+    config: ObjectTransmissionInformation,
+    decoder: Decoder,
+    symbols_needed: usize,
+    seen: HashSet<Vec<u8>>,
+}
+
+impl SymbolCollector {
+    ///
+    /// A collector for the data described by a symbol's 12 byte configuration, or `None` if
+    /// it describes something the decoder can't rebuild.
+    ///
+    pub fn new(config: &[u8]) -> Option<Self> {
+        let config = ObjectTransmissionInformation::deserialize(config.try_into().ok()?);
+        let symbol_size = config.symbol_size() as u64;
+        let alignment = config.symbol_alignment() as u64;
+        let source_blocks = config.source_blocks() as u64;
+        let sub_blocks = config.sub_blocks() as u64;
+        if symbol_size == 0 || alignment == 0 || !symbol_size.is_multiple_of(alignment) {
+            return None;
+        }
+        if source_blocks == 0 || sub_blocks == 0 || sub_blocks > symbol_size / alignment {
+            return None;
+        }
+        let transfer_length = config.transfer_length();
+        if transfer_length == 0 || transfer_length > MAX_TRANSFER_LENGTH {
+            return None;
+        }
+        let symbols_needed = (transfer_length + symbol_size - 1) / symbol_size;
+        if (symbols_needed + source_blocks - 1) / source_blocks > MAX_SOURCE_SYMBOLS_PER_BLOCK {
+            return None;
+        }
+        return Some(Self {
+            config,
+            decoder: Decoder::new(config),
+            symbols_needed: symbols_needed as usize,
+            seen: HashSet::new(),
+        });
+    }
+
+    pub fn symbols_needed(&self) -> usize {
+        return self.symbols_needed;
+    }
+
+    pub fn symbols_received(&self) -> usize {
+        return self.seen.len();
+    }
+
+    ///
+    /// Add a symbol, returning the reconstructed data once there are enough of them.  The
+    /// camera sees each symbol many times so repeats are ignored, as is anything that doesn't
+    /// fit the configuration.
+    ///
+    pub fn add(&mut self, symbol: &[u8]) -> Option<Vec<u8>> {
+        if symbol.len() != 4 + self.config.symbol_size() as usize || symbol[0] >= self.config.source_blocks() {
+            return None;
+        }
+        if !self.seen.insert(symbol[0..4].to_vec()) {
+            return None;
+        }
+        return self.decoder.decode(EncodingPacket::deserialize(symbol));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        return (0..5000u32).map(|i| (i * 31 % 251) as u8).collect();
+    }
+
+    #[test]
+    fn data_comes_back_from_every_symbol() {
+        let mut stream = SymbolStream::new(&data(), 100);
+        let mut collector = SymbolCollector::new(&stream.get_config()).unwrap();
+        let mut rebuilt = None;
+        while rebuilt.is_none() {
+            rebuilt = collector.add(&stream.next_symbol());
+        }
+        assert_eq!(rebuilt, Some(data()));
+        // the source symbols come first, so nothing more is needed
+        assert_eq!(collector.symbols_received(), collector.symbols_needed());
+    }
+
+    #[test]
+    fn data_comes_back_after_dropped_and_repeated_symbols() {
+        let mut stream = SymbolStream::new(&data(), 100);
+        let mut collector = SymbolCollector::new(&stream.get_config()).unwrap();
+        let mut rebuilt = None;
+        let mut shown = 0;
+        while rebuilt.is_none() {
+            let symbol = stream.next_symbol();
+            shown += 1;
+            // the camera misses two in every three, and sees the rest twice
+            if shown % 3 != 0 {
+                continue;
+            }
+            rebuilt = collector.add(&symbol);
+            assert_eq!(collector.add(&symbol), None);
+        }
+        assert_eq!(rebuilt, Some(data()));
+        assert!(shown >= 3 * collector.symbols_needed());
+    }
+
+    #[test]
+    fn symbols_that_dont_fit_are_ignored() {
+        let mut stream = SymbolStream::new(&data(), 100);
+        let mut collector = SymbolCollector::new(&stream.get_config()).unwrap();
+        let symbol = stream.next_symbol();
+        assert_eq!(collector.add(&symbol[0..50]), None);
+        let mut other_block = symbol.clone();
+        other_block[0] = 1;
+        assert_eq!(collector.add(&other_block), None);
+        assert_eq!(collector.symbols_received(), 0);
+    }
+
+    #[test]
+    fn configurations_nobody_can_rebuild_are_refused() {
+        assert!(SymbolCollector::new(&[0; 12]).is_none());
+        assert!(SymbolCollector::new(&[0; 11]).is_none());
+    }
+}
@@ -1,91 +1,456 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 
 use bytes::BufMut;
 
-static COUNTER: AtomicUsize = AtomicUsize::new(0);
+use crate::handshake::{Capabilities, CAPABILITIES_SIZE};
+use crate::hash::{Hash, HASH_SIZE};
+use crate::identity::{IDENTITY_KEY_SIZE, IdentityKey, Signature, SIGNATURE_SIZE};
+use crate::metadata::FileMetadata;
+use crate::pairing::{PublicKey, PUBLIC_KEY_SIZE};
+use crate::reason::Reason;
+use crate::session::{Session, SESSION_UNBOUND};
+
+pub const FRAME_MAGIC: u8 = 0xC7;
+pub const FRAME_VERSION_1: u8 = 0x01;
+pub const FRAME_VERSION: u8 = 0x02;
 
 pub const FRAME_TYPE_CTS: u8 = 0x01;
 pub const FRAME_TYPE_DONE: u8 = 0x02;
 pub const FRAME_TYPE_SEGMENT: u8 = 0x03;
+pub const FRAME_TYPE_SYMBOL: u8 = 0x04;
+pub const FRAME_TYPE_HELLO: u8 = 0x05;
+pub const FRAME_TYPE_HELLO_ACK: u8 = 0x06;
+pub const FRAME_TYPE_METADATA: u8 = 0x07;
+pub const FRAME_TYPE_VERIFIED: u8 = 0x08;
+pub const FRAME_TYPE_KEY: u8 = 0x09;
+pub const FRAME_TYPE_RESUME: u8 = 0x0A;
+pub const FRAME_TYPE_ABORT: u8 = 0x0B;
+pub const FRAME_TYPE_ERROR: u8 = 0x0C;
+pub const FRAME_TYPE_SEALED: u8 = 0x0D;
+
+/// magic, version, flags, type, session, sequence
+const HEADER_SIZE: usize = 1 + 1 + 1 + 1 + 4 + 4;
+/// crc32 of everything before it
+const CRC_SIZE: usize = 4;
+/// Everything in a segment frame that isn't data
+pub const SEGMENT_OVERHEAD: usize = HEADER_SIZE + 8 + 8 + CRC_SIZE;
+/// The most of an ABORT or ERROR's explanation that's sent, it only has to fit on a line
+const MAX_REASON_TEXT: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    TooShort(usize),
+    /// Some other code, or a v1 frame when they aren't being read
+    BadMagic(u8),
+    UnsupportedVersion(u8),
+    BadCrc,
+    UnknownType(u8),
+    BadLength(u8, usize),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        return match self {
+            FrameError::TooShort(len) => write!(f, "{} bytes is too short for a frame", len),
+            FrameError::BadMagic(magic) => write!(f, "not a frame, it starts with {:02x}", magic),
+            FrameError::UnsupportedVersion(version) => write!(f, "unsupported frame version {}", version),
+            FrameError::BadCrc => write!(f, "crc mismatch"),
+            FrameError::UnknownType(frame_type) => write!(f, "unknown frame type {}", frame_type),
+            FrameError::BadLength(frame_type, len) => write!(f, "{} bytes is the wrong size for frame type {}", len, frame_type),
+        };
+    }
+}
+
+impl Error for FrameError {}
+
+///
+/// The body of a frame, borrowed from its encoding.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedFrame<'a> {
+    Cts { segment_offset: usize, received: &'a [u8] },
+    /// The sender's hash of everything it sent, if it knows it, and its signature over that
+    Done(Option<Hash>, Option<Signature>),
+    Segment { segment_offset: usize, segment_count: usize, data: &'a [u8] },
+    Symbol { config: &'a [u8], symbol: &'a [u8] },
+    /// Along with the sender's pairing key, if it has one
+    Hello(Capabilities, Option<PublicKey>),
+    HelloAck(Capabilities, Option<PublicKey>),
+    Metadata(FileMetadata),
+    /// The receiver's hash of everything it wrote
+    Verified(Hash),
+    /// Someone's identity key, shown to be imported rather than as part of a transfer
+    Key(IdentityKey),
+    /// A CTS from a receiver that already has some of the file with this hash
+    Resume { hash: Hash, segment_offset: usize, received: &'a [u8] },
+    /// The other side was told to stop
+    Abort(Reason, String),
+    /// The other side couldn't go on
+    Error(Reason, String),
+    /// A frame of another type whose body was encrypted, for the passphrase to open
+    Sealed { frame_type: u8, body: &'a [u8] },
+}
 
 ///
 /// The thing that's exchanged
 ///
+/// ```text
+/// | magic u8 | version u8 | flags u8 | type u8 | session u32 | sequence u32 | body ... | crc32 u32 |
+/// ```
+///
 #[derive(Debug, Clone)]
 pub struct Frame {
     encoded: Vec<u8>,
 }
 
 impl Frame {
-    pub fn new(encoded: Vec<u8>) -> Self {
-        return Self {
+    ///
+    /// Check something read off a code really is a frame.  With `v1`, anything without the
+    /// magic is taken for a v1 frame, which had no header or crc, and rewritten in the
+    /// current layout.  Only this rewriting makes headers that say version 1, so none are
+    /// accepted from a code.
+    ///
+    pub fn decode(encoded: Vec<u8>, v1: bool) -> Result<Self, FrameError> {
+        if v1 && encoded.first() != Some(&FRAME_MAGIC) {
+            let (sequence, parsed) = Self::parse_v1(&encoded)?;
+            return Ok(match parsed {
+                ParsedFrame::Cts { segment_offset, .. } => Self::build(FRAME_VERSION_1, SESSION_UNBOUND, sequence, FRAME_TYPE_CTS, 8, |e| {
+                    e.put_u64(segment_offset as u64);
+                }),
+                ParsedFrame::Segment { segment_offset, segment_count, data } => Self::build(FRAME_VERSION_1, SESSION_UNBOUND, sequence, FRAME_TYPE_SEGMENT, 8 + 8 + data.len(), |e| {
+                    e.put_u64(segment_offset as u64);
+                    e.put_u64(segment_count as u64);
+                    e.put_slice(data);
+                }),
+                _ => Self::build(FRAME_VERSION_1, SESSION_UNBOUND, sequence, FRAME_TYPE_DONE, 0, |_| {}),
+            });
+        }
+        if encoded.get(1) == Some(&FRAME_VERSION_1) {
+            return Err(FrameError::UnsupportedVersion(FRAME_VERSION_1));
+        }
+        Self::parse(&encoded)?;
+        return Ok(Self {
             encoded
+        });
+    }
+
+    ///
+    /// Validate an encoded frame and pick its body apart.
+    ///
+    pub fn parse(encoded: &[u8]) -> Result<ParsedFrame<'_>, FrameError> {
+        match encoded.first() {
+            Some(&FRAME_MAGIC) => {}
+            Some(&magic) => return Err(FrameError::BadMagic(magic)),
+            None => return Err(FrameError::TooShort(0)),
+        }
+        if encoded.len() < HEADER_SIZE + CRC_SIZE {
+            return Err(FrameError::TooShort(encoded.len()));
         }
+        // v1 frames rewritten by decode keep their original version
+        if encoded[1] != FRAME_VERSION && encoded[1] != FRAME_VERSION_1 {
+            return Err(FrameError::UnsupportedVersion(encoded[1]));
+        }
+        let (content, crc) = encoded.split_at(encoded.len() - CRC_SIZE);
+        if crc32fast::hash(content).to_be_bytes() != crc {
+            return Err(FrameError::BadCrc);
+        }
+        let frame_type = content[3];
+        let body = &content[HEADER_SIZE..];
+        if encoded[1] == FRAME_VERSION_1 && !Self::is_rewritten_v1(content, frame_type, body.len()) {
+            return Err(FrameError::UnsupportedVersion(FRAME_VERSION_1));
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(body[i..i + 8].try_into().unwrap()) as usize;
+        return match (frame_type, body.len()) {
+            (FRAME_TYPE_CTS, len) if len >= 8 => Ok(ParsedFrame::Cts {
+                segment_offset: u64_at(0),
+                received: &body[8..],
+            }),
+            (FRAME_TYPE_DONE, 0) => Ok(ParsedFrame::Done(None, None)),
+            (FRAME_TYPE_DONE, HASH_SIZE) => Ok(ParsedFrame::Done(Some(body.try_into().unwrap()), None)),
+            (FRAME_TYPE_DONE, len) if len == HASH_SIZE + SIGNATURE_SIZE => Ok(ParsedFrame::Done(
+                Some(body[0..HASH_SIZE].try_into().unwrap()),
+                Signature::decode(&body[HASH_SIZE..]),
+            )),
+            (FRAME_TYPE_SEGMENT, len) if len >= 8 + 8 => Ok(ParsedFrame::Segment {
+                segment_offset: u64_at(0),
+                segment_count: u64_at(8),
+                data: &body[16..],
+            }),
+            (FRAME_TYPE_SYMBOL, len) if len >= 12 + 4 => Ok(ParsedFrame::Symbol {
+                config: &body[0..12],
+                symbol: &body[12..],
+            }),
+            (FRAME_TYPE_HELLO, len) if len >= CAPABILITIES_SIZE => Ok(ParsedFrame::Hello(Capabilities::decode(body), Self::pairing_key(body))),
+            (FRAME_TYPE_HELLO_ACK, len) if len >= CAPABILITIES_SIZE => Ok(ParsedFrame::HelloAck(Capabilities::decode(body), Self::pairing_key(body))),
+            (FRAME_TYPE_METADATA, len) => match FileMetadata::decode(body) {
+                Some(metadata) => Ok(ParsedFrame::Metadata(metadata)),
+                None => Err(FrameError::BadLength(frame_type, len)),
+            },
+            (FRAME_TYPE_VERIFIED, HASH_SIZE) => Ok(ParsedFrame::Verified(body.try_into().unwrap())),
+            (FRAME_TYPE_KEY, IDENTITY_KEY_SIZE) => Ok(ParsedFrame::Key(body.try_into().unwrap())),
+            (FRAME_TYPE_RESUME, len) if len >= HASH_SIZE + 8 => Ok(ParsedFrame::Resume {
+                hash: body[0..HASH_SIZE].try_into().unwrap(),
+                segment_offset: u64_at(HASH_SIZE),
+                received: &body[HASH_SIZE + 8..],
+            }),
+            (FRAME_TYPE_ABORT, len) if len >= 1 => Ok(ParsedFrame::Abort(Reason::from_code(body[0]), Self::reason_text(body))),
+            (FRAME_TYPE_ERROR, len) if len >= 1 => Ok(ParsedFrame::Error(Reason::from_code(body[0]), Self::reason_text(body))),
+            (FRAME_TYPE_SEALED, len) if len >= 1 => Ok(ParsedFrame::Sealed {
+                frame_type: body[0],
+                body: &body[1..],
+            }),
+            (FRAME_TYPE_CTS, len) | (FRAME_TYPE_DONE, len) | (FRAME_TYPE_SEGMENT, len) | (FRAME_TYPE_SYMBOL, len) |
+            (FRAME_TYPE_HELLO, len) | (FRAME_TYPE_HELLO_ACK, len) | (FRAME_TYPE_VERIFIED, len) | (FRAME_TYPE_KEY, len) |
+            (FRAME_TYPE_RESUME, len) | (FRAME_TYPE_ABORT, len) | (FRAME_TYPE_ERROR, len) | (FRAME_TYPE_SEALED, len) => {
+                Err(FrameError::BadLength(frame_type, len))
+            }
+            _ => Err(FrameError::UnknownType(frame_type)),
+        };
     }
 
-    pub fn new_cts(segment_offset: usize) -> Self {
-        let mut encoded: Vec<u8> = Vec::with_capacity(4 + 1 + 4);
-        encoded.put_u32(COUNTER.fetch_add(1, Ordering::AcqRel) as u32);
-        encoded.put_u8(FRAME_TYPE_CTS);
-        encoded.put_u32(segment_offset as u32);
-        return Self {
-            encoded
+    ///
+    /// Peers that can pair put their public key after their capabilities.
+    ///
+    fn pairing_key(body: &[u8]) -> Option<PublicKey> {
+        return body.get(CAPABILITIES_SIZE..CAPABILITIES_SIZE + PUBLIC_KEY_SIZE)
+            .map(|key| key.try_into().unwrap());
+    }
+
+    fn reason_text(body: &[u8]) -> String {
+        return String::from_utf8_lossy(&body[1..]).into_owned();
+    }
+
+    ///
+    /// Does a version 1 header hold one of the frames v1 had, as `decode` rewrites them?
+    ///
+    fn is_rewritten_v1(content: &[u8], frame_type: u8, body_len: usize) -> bool {
+        let unbound = content[2] == 0 && u32::from_be_bytes(content[4..8].try_into().unwrap()) == SESSION_UNBOUND;
+        return unbound && match frame_type {
+            FRAME_TYPE_CTS => body_len == 8,
+            FRAME_TYPE_DONE => body_len == 0,
+            FRAME_TYPE_SEGMENT => body_len >= 8 + 8,
+            _ => false,
+        };
+    }
+
+    ///
+    /// v1 frames were `| sequence u32 | type u8 | offset u32 | count u32 | data ... |`
+    ///
+    fn parse_v1(encoded: &[u8]) -> Result<(u32, ParsedFrame<'_>), FrameError> {
+        if encoded.len() < 5 {
+            return Err(FrameError::TooShort(encoded.len()));
         }
+        let sequence = u32::from_be_bytes(encoded[0..4].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_be_bytes(encoded[i..i + 4].try_into().unwrap()) as usize;
+        let parsed = match (encoded[4], encoded.len()) {
+            (FRAME_TYPE_CTS, 9) => ParsedFrame::Cts {
+                segment_offset: u32_at(5),
+                received: &[],
+            },
+            (FRAME_TYPE_DONE, 5) => ParsedFrame::Done(None, None),
+            (FRAME_TYPE_SEGMENT, len) if len >= 13 => ParsedFrame::Segment {
+                segment_offset: u32_at(5),
+                segment_count: u32_at(9),
+                data: &encoded[13..],
+            },
+            (FRAME_TYPE_CTS, len) | (FRAME_TYPE_DONE, len) | (FRAME_TYPE_SEGMENT, len) => {
+                return Err(FrameError::BadLength(encoded[4], len));
+            }
+            (frame_type, _) => return Err(FrameError::UnknownType(frame_type)),
+        };
+        return Ok((sequence, parsed));
     }
 
-    pub fn new_done() -> Self {
-        let mut encoded: Vec<u8> = Vec::with_capacity(4 + 1);
-        encoded.put_u32(COUNTER.fetch_add(1, Ordering::AcqRel) as u32);
-        encoded.put_u8(FRAME_TYPE_DONE);
+    fn build<F>(version: u8, session: u32, sequence: u32, frame_type: u8, body_size: usize, body: F) -> Self
+        where F: FnOnce(&mut Vec<u8>) {
+        let mut encoded: Vec<u8> = Vec::with_capacity(HEADER_SIZE + body_size + CRC_SIZE);
+        encoded.put_u8(FRAME_MAGIC);
+        encoded.put_u8(version);
+        encoded.put_u8(0);
+        encoded.put_u8(frame_type);
+        encoded.put_u32(session);
+        encoded.put_u32(sequence);
+        body(&mut encoded);
+        encoded.put_u32(crc32fast::hash(&encoded));
         return Self {
             encoded
         }
     }
 
-    pub fn new_segment<D>(segment_offset: usize, segment_count: usize, data: D) -> Self
+    pub fn new_hello(session: &Session, capabilities: &Capabilities, key: Option<&PublicKey>) -> Self {
+        let key: &[u8] = key.map_or(&[], |key| key);
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_HELLO, CAPABILITIES_SIZE + key.len(), |encoded| {
+            capabilities.encode(encoded);
+            encoded.put_slice(key);
+        });
+    }
+
+    pub fn new_hello_ack(session: &Session, capabilities: &Capabilities, key: Option<&PublicKey>) -> Self {
+        let key: &[u8] = key.map_or(&[], |key| key);
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_HELLO_ACK, CAPABILITIES_SIZE + key.len(), |encoded| {
+            capabilities.encode(encoded);
+            encoded.put_slice(key);
+        });
+    }
+
+    pub fn new_metadata(session: &Session, metadata: &FileMetadata) -> Self {
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_METADATA, metadata.encoded_size(), |encoded| {
+            metadata.encode(encoded);
+        });
+    }
+
+    ///
+    /// A clear-to-send frame.  `segment_offset` is the first segment the receiver is still
+    /// missing and `received` is a bitmap of the segments following it that have already
+    /// arrived (bit `i` of the bitmap is segment `segment_offset + i`).
+    ///
+    pub fn new_cts(session: &Session, segment_offset: usize, received: &[u8]) -> Self {
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_CTS, 8 + received.len(), |encoded| {
+            encoded.put_u64(segment_offset as u64);
+            encoded.put_slice(received);
+        });
+    }
+
+    ///
+    /// A CTS asking the sender to pick up where an earlier transfer of the file with `hash`
+    /// left off.
+    ///
+    pub fn new_resume(session: &Session, hash: &Hash, segment_offset: usize, received: &[u8]) -> Self {
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_RESUME, HASH_SIZE + 8 + received.len(), |encoded| {
+            encoded.put_slice(hash);
+            encoded.put_u64(segment_offset as u64);
+            encoded.put_slice(received);
+        });
+    }
+
+    ///
+    /// The sender has nothing more to send.  It includes a hash of everything it sent when it
+    /// managed to read it all.
+    ///
+    pub fn new_done(session: &Session, hash: Option<&Hash>) -> Self {
+        let hash: &[u8] = hash.map_or(&[], |hash| hash);
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_DONE, hash.len(), |encoded| {
+            encoded.put_slice(hash);
+        });
+    }
+
+    ///
+    /// DONE along with our signature over the hash.
+    ///
+    pub fn new_signed_done(session: &Session, hash: &Hash, signature: &Signature) -> Self {
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_DONE, HASH_SIZE + SIGNATURE_SIZE, |encoded| {
+            encoded.put_slice(hash);
+            signature.encode(encoded);
+        });
+    }
+
+    ///
+    /// An identity key to be scanned into someone's keystore.  It belongs to no session.
+    ///
+    pub fn new_key(key: &IdentityKey) -> Self {
+        return Self::build(FRAME_VERSION, SESSION_UNBOUND, 0, FRAME_TYPE_KEY, IDENTITY_KEY_SIZE, |encoded| {
+            encoded.put_slice(key);
+        });
+    }
+
+    ///
+    /// The receiver's answer to DONE: the hash of what it actually wrote.
+    ///
+    pub fn new_verified(session: &Session, hash: &Hash) -> Self {
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_VERIFIED, HASH_SIZE, |encoded| {
+            encoded.put_slice(hash);
+        });
+    }
+
+    ///
+    /// We've stopped: ABORT when we were told to, ERROR when we couldn't go on.
+    ///
+    pub fn new_abort(session: &Session, reason: Reason, text: &str) -> Self {
+        let frame_type = if reason == Reason::Cancelled { FRAME_TYPE_ABORT } else { FRAME_TYPE_ERROR };
+        let mut len = text.len().min(MAX_REASON_TEXT);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let text = &text.as_bytes()[0..len];
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), frame_type, 1 + text.len(), |encoded| {
+            encoded.put_u8(reason.code());
+            encoded.put_slice(text);
+        });
+    }
+
+    pub fn new_segment<D>(session: &Session, segment_offset: usize, segment_count: usize, data: D) -> Self
         where D: AsRef<[u8]> {
         let d = data.as_ref();
-        let mut encoded: Vec<u8> = Vec::with_capacity(4 + 1 + 4 + 4 + d.len());
-        encoded.put_u32(COUNTER.fetch_add(1, Ordering::AcqRel) as u32);
-        encoded.put_u8(FRAME_TYPE_SEGMENT);
-        encoded.put_u32(segment_offset as u32);
-        encoded.put_u32(segment_count as u32);
-        encoded.put_slice(d);
-        return Self {
-            encoded
-        };
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_SEGMENT, 8 + 8 + d.len(), |encoded| {
+            encoded.put_u64(segment_offset as u64);
+            encoded.put_u64(segment_count as u64);
+            encoded.put_slice(d);
+        });
     }
 
-    pub fn get_sequence(&self) -> usize {
-        return u32::from_be_bytes(self.encoded[0..4].try_into().unwrap()) as usize;
+    ///
+    /// A fountain coded symbol.  Every symbol carries the encoding configuration so the
+    /// receiver can start collecting from whichever one it sees first.
+    ///
+    pub fn new_symbol<D>(session: &Session, config: &[u8], symbol: D) -> Self
+        where D: AsRef<[u8]> {
+        let d = symbol.as_ref();
+        return Self::build(FRAME_VERSION, session.id(), session.next_sequence(), FRAME_TYPE_SYMBOL, config.len() + d.len(), |encoded| {
+            encoded.put_slice(config);
+            encoded.put_slice(d);
+        });
     }
 
-    pub fn get_type(&self) -> u8 {
-        return self.encoded[4];
+    ///
+    /// This frame with its body swapped for `sealed`, the same body encrypted.
+    ///
+    pub fn to_sealed(&self, sealed: &[u8]) -> Self {
+        return Self::build(self.get_version(), self.get_session(), self.get_sequence(), FRAME_TYPE_SEALED, 1 + sealed.len(), |encoded| {
+            encoded.put_u8(self.get_type());
+            encoded.put_slice(sealed);
+        });
     }
 
-    pub fn get_segment_offset(&self) -> usize {
-        return u32::from_be_bytes(self.encoded[5..9].try_into().unwrap()) as usize;
+    ///
+    /// The frame a SEALED one stood for, from its opened body.
+    ///
+    pub fn to_opened(&self, frame_type: u8, body: &[u8]) -> Result<Self, FrameError> {
+        let opened = Self::build(self.get_version(), self.get_session(), self.get_sequence(), frame_type, body.len(), |encoded| {
+            encoded.put_slice(body);
+        });
+        Self::parse(&opened.encoded)?;
+        return Ok(opened);
     }
 
-    pub fn get_segment_count(&self) -> usize {
-        u32::from_be_bytes(self.encoded[9..13].try_into().unwrap()) as usize
+    pub fn get_version(&self) -> u8 {
+        return self.encoded[1];
+    }
+
+    pub fn get_type(&self) -> u8 {
+        return self.encoded[3];
     }
 
-    pub fn get_data(&self) -> &[u8] {
-        return &self.encoded[13..];
+    pub fn get_session(&self) -> u32 {
+        return u32::from_be_bytes(self.encoded[4..8].try_into().unwrap());
     }
 
-    pub fn is_done(&self) -> bool {
-        return self.get_type() == FRAME_TYPE_DONE;
+    pub fn get_sequence(&self) -> u32 {
+        return u32::from_be_bytes(self.encoded[8..12].try_into().unwrap());
     }
 
-    pub fn is_segment(&self) -> bool {
-        return self.get_type() == FRAME_TYPE_SEGMENT;
+    ///
+    /// Everything between the header and the crc.
+    ///
+    pub fn body(&self) -> &[u8] {
+        return &self.encoded[HEADER_SIZE..self.encoded.len() - CRC_SIZE];
     }
 
-    pub fn is_cts(&self) -> bool {
-        return self.get_type() == FRAME_TYPE_CTS;
+    ///
+    /// Frames are validated when they're built or decoded so this can't fail.
+    ///
+    pub fn parsed(&self) -> ParsedFrame<'_> {
+        return Self::parse(&self.encoded).expect("Frame was not validated");
     }
 }
 
@@ -93,4 +458,71 @@ impl AsRef<[u8]> for Frame {
     fn as_ref(&self) -> &[u8] {
         return &self.encoded;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(frame: &Frame) -> Frame {
+        return Frame::decode(frame.as_ref().to_vec(), false).unwrap();
+    }
+
+    #[test]
+    fn frames_survive_a_round_trip() {
+        let session = Session::random();
+        let segment = Frame::new_segment(&session, 3, 9, [1, 2, 3]);
+        assert_eq!(decoded(&segment).parsed(), ParsedFrame::Segment { segment_offset: 3, segment_count: 9, data: &[1, 2, 3] });
+        let cts = Frame::new_cts(&session, 5, &[0b101]);
+        assert_eq!(decoded(&cts).parsed(), ParsedFrame::Cts { segment_offset: 5, received: &[0b101] });
+        let done = Frame::new_done(&session, Some(&[4; HASH_SIZE]));
+        assert_eq!(decoded(&done).parsed(), ParsedFrame::Done(Some([4; HASH_SIZE]), None));
+        let abort = Frame::new_abort(&session, Reason::DiskFull, "full");
+        assert_eq!(decoded(&abort).parsed(), ParsedFrame::Error(Reason::DiskFull, "full".to_string()));
+        assert_eq!(decoded(&abort).get_session(), session.id());
+        assert_eq!(decoded(&abort).get_sequence(), abort.get_sequence());
+    }
+
+    #[test]
+    fn a_damaged_frame_fails_its_crc() {
+        let frame = Frame::new_segment(&Session::random(), 0, 1, [1, 2, 3]);
+        let mut encoded = frame.as_ref().to_vec();
+        encoded[HEADER_SIZE + 16] ^= 1;
+        assert_eq!(Frame::decode(encoded, false).unwrap_err(), FrameError::BadCrc);
+    }
+
+    #[test]
+    fn short_or_foreign_payloads_are_refused() {
+        assert_eq!(Frame::decode(vec![FRAME_MAGIC, FRAME_VERSION], false).unwrap_err(), FrameError::TooShort(2));
+        let foreign = b"https://example.com/not-a-frame".to_vec();
+        assert_eq!(Frame::decode(foreign, false).unwrap_err(), FrameError::BadMagic(b'h'));
+        let mut newer = Frame::new_done(&Session::random(), None).as_ref().to_vec();
+        newer[1] = FRAME_VERSION + 1;
+        assert_eq!(Frame::decode(newer, false).unwrap_err(), FrameError::UnsupportedVersion(FRAME_VERSION + 1));
+    }
+
+    #[test]
+    fn v1_frames_are_only_read_when_asked() {
+        let v1 = vec![0, 0, 0, 7, FRAME_TYPE_SEGMENT, 0, 0, 0, 2, 0, 0, 0, 5, 9, 9];
+        assert!(matches!(Frame::decode(v1.clone(), false), Err(FrameError::BadMagic(0))));
+        let frame = Frame::decode(v1, true).unwrap();
+        assert_eq!(frame.get_version(), FRAME_VERSION_1);
+        assert_eq!(frame.get_session(), SESSION_UNBOUND);
+        assert_eq!(frame.get_sequence(), 7);
+        assert_eq!(frame.parsed(), ParsedFrame::Segment { segment_offset: 2, segment_count: 5, data: &[9, 9] });
+        let done = Frame::decode(vec![0, 0, 0, 1, FRAME_TYPE_DONE], true).unwrap();
+        assert_eq!(done.parsed(), ParsedFrame::Done(None, None));
+    }
+
+    #[test]
+    fn version_1_headers_are_only_made_by_rewriting() {
+        let rewritten = Frame::decode(vec![0, 0, 0, 1, FRAME_TYPE_DONE], true).unwrap();
+        // read off a code they're refused, even with v1 frames allowed
+        assert_eq!(Frame::decode(rewritten.as_ref().to_vec(), true).unwrap_err(), FrameError::UnsupportedVersion(FRAME_VERSION_1));
+        let mut verified = Frame::new_verified(&Session::random(), &[1; HASH_SIZE]).as_ref().to_vec();
+        verified[1] = FRAME_VERSION_1;
+        let crc_at = verified.len() - CRC_SIZE;
+        let crc = crc32fast::hash(&verified[..crc_at]).to_be_bytes();
+        verified[crc_at..].copy_from_slice(&crc);
+        assert_eq!(Frame::parse(&verified).unwrap_err(), FrameError::UnsupportedVersion(FRAME_VERSION_1));
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::sync::OnceLock;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode};
+use image::{ColorType, GrayImage, RgbImage};
+use image::codecs::png::PngEncoder;
+
+use crate::codec::Rendering;
+
+/// What a character cell is taken to be in pixels when the terminal doesn't say
+pub const CELL_WIDTH: u32 = 8;
+pub const CELL_HEIGHT: u32 = 16;
+
+/// A one pixel kitty image to query with, the text area size in pixels, and the primary
+/// device attributes.  Every terminal answers the last, so the answers end with it.
+const QUERIES: &[u8] = b"\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\\x1b[14t\x1b[c";
+/// How long the terminal gets to answer before we take it that it won't
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The most of an image kitty takes in one escape
+const KITTY_CHUNK: usize = 4096;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+///
+/// What the terminal said about itself when asked.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Answers {
+    /// It answered the kitty graphics query
+    kitty: bool,
+    /// Its device attributes include 4, sixel graphics
+    sixel: bool,
+    /// How big its text area is in pixels, across and down
+    pixels: Option<(u32, u32)>,
+}
+
+impl Answers {
+    fn parse(reply: &[u8]) -> Self {
+        let reply = String::from_utf8_lossy(reply);
+        let sequence = |start: &str, end: char| {
+            let at = reply.find(start)? + start.len();
+            return reply[at..].find(end).map(|length| reply[at..at + length].to_string());
+        };
+        let attributes = sequence("\x1b[?", 'c').unwrap_or_default();
+        let pixels = sequence("\x1b[4;", 't').and_then(|size| {
+            let (height, width) = size.split_once(';')?;
+            return Some((width.parse().ok()?, height.parse().ok()?));
+        });
+        return Self {
+            kitty: sequence("\x1b_Gi=31;", '\x1b').is_some_and(|status| status == "OK"),
+            sixel: attributes.split(';').any(|attribute| attribute == "4"),
+            pixels: pixels.filter(|&(width, height)| width > 0 && height > 0),
+        };
+    }
+
+    ///
+    /// Whether the answers are all in, which they are once the device attributes are.
+    ///
+    fn is_complete(reply: &[u8]) -> bool {
+        return reply.windows(3).position(|start| start == b"\x1b[?")
+            .is_some_and(|at| reply[at..].contains(&b'c'));
+    }
+}
+
+///
+/// Ask the terminal what it can draw, once.  A terminal that doesn't answer in time, or
+/// no terminal at all, is taken to draw nothing but characters.
+///
+fn answers() -> Answers {
+    static ANSWERS: OnceLock<Answers> = OnceLock::new();
+    return *ANSWERS.get_or_init(|| ask().map(|reply| Answers::parse(&reply)).unwrap_or_default());
+}
+
+fn ask() -> Option<Vec<u8>> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").ok()?;
+    let mut reader = tty.try_clone().ok()?;
+    // the answers come in like key presses, they mustn't wait for a newline or be echoed
+    enable_raw_mode().ok()?;
+    let (tx, rx) = channel();
+    // reads block, so a terminal that never answers leaves this waiting on its own
+    thread::spawn(move || {
+        let mut reply = Vec::new();
+        let mut buf = [0u8; 256];
+        while !Answers::is_complete(&reply) {
+            match reader.read(&mut buf) {
+                Ok(size) if size > 0 => reply.extend_from_slice(&buf[0..size]),
+                _ => break,
+            }
+        }
+        let _ = tx.send(reply);
+    });
+    let reply = tty.write_all(QUERIES).and_then(|_| tty.flush()).ok()
+        .and_then(|_| rx.recv_timeout(QUERY_TIMEOUT).ok());
+    let _ = disable_raw_mode();
+    return reply;
+}
+
+///
+/// Which bitmap protocol the terminal we're in speaks, going by what it answers when asked.
+///
+pub fn detect() -> Option<Rendering> {
+    let answers = answers();
+    if answers.kitty {
+        return Some(Rendering::Kitty);
+    }
+    if answers.sixel {
+        return Some(Rendering::Sixel);
+    }
+    return None;
+}
+
+///
+/// How many pixels across and down a character cell is, if the terminal says.
+///
+pub fn cell_size() -> Option<(u32, u32)> {
+    let (width, height) = answers().pixels?;
+    let (columns, rows) = terminal::size().ok()?;
+    if columns == 0 || rows == 0 {
+        return None;
+    }
+    return Some((width / columns as u32, height / rows as u32)).filter(|&(width, height)| width > 0 && height > 0);
+}
+
+///
+/// Draw the image with sixels: a light and a dark colour, six rows of pixels at a time.
+///
+pub fn sixel(image: &GrayImage) -> String {
+    let (width, height) = image.dimensions();
+    return sixels(width, height, 2, |x, y| (image.get_pixel(x, y)[0] > 127) as usize);
+}
+
+///
+/// Draw the image with sixels in the eight colours that are each of red, green and blue either
+/// on or off.
+///
+pub fn sixel_rgb(image: &RgbImage) -> String {
+    let (width, height) = image.dimensions();
+    return sixels(width, height, 8, |x, y| {
+        let pixel = image.get_pixel(x, y);
+        return (0..3).filter(|channel| pixel[*channel] > 127).map(|channel| 1 << channel).sum();
+    });
+}
+
+///
+/// Colour `i` of the palette has red, green and blue on for bits 0, 1 and 2.
+///
+fn sixels<F: Fn(u32, u32) -> usize>(width: u32, height: u32, colors: usize, color_of: F) -> String {
+    let mut encoded = format!("\x1bPq\"1;1;{};{}", width, height);
+    for color in 0..colors {
+        // two colours are black and white
+        let on = |bit: usize| if colors == 2 { color * 100 } else { ((color >> bit) & 1) * 100 };
+        encoded.push_str(&format!("#{};2;{};{};{}", color, on(0), on(1), on(2)));
+    }
+    for band in (0..height).step_by(6) {
+        for color in 0..colors {
+            encoded.push_str(&format!("#{}", color));
+            let mut run: Option<(u8, usize)> = None;
+            for x in 0..width {
+                let mut sixel = 0;
+                for dy in 0..6.min(height - band) {
+                    if color_of(x, band + dy) == color {
+                        sixel |= 1 << dy;
+                    }
+                }
+                let sixel = b'?' + sixel;
+                run = match run {
+                    Some((c, count)) if c == sixel => Some((c, count + 1)),
+                    Some((c, count)) => {
+                        push_run(&mut encoded, c, count);
+                        Some((sixel, 1))
+                    }
+                    None => Some((sixel, 1)),
+                };
+            }
+            if let Some((c, count)) = run {
+                push_run(&mut encoded, c, count);
+            }
+            encoded.push('$');
+        }
+        encoded.push('-');
+    }
+    encoded.push_str("\x1b\\");
+    return encoded;
+}
+
+fn push_run(encoded: &mut String, sixel: u8, count: usize) {
+    if count > 3 {
+        encoded.push_str(&format!("!{}", count));
+    } else {
+        for _ in 1..count {
+            encoded.push(sixel as char);
+        }
+    }
+    encoded.push(sixel as char);
+}
+
+///
+/// Draw the image with kitty's graphics protocol, replacing whatever we drew before.
+///
+pub fn kitty(image: &GrayImage) -> String {
+    return kitty_png(image.as_raw(), image.width(), image.height(), ColorType::L8);
+}
+
+pub fn kitty_rgb(image: &RgbImage) -> String {
+    return kitty_png(image.as_raw(), image.width(), image.height(), ColorType::Rgb8);
+}
+
+fn kitty_png(pixels: &[u8], width: u32, height: u32, color_type: ColorType) -> String {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .encode(pixels, width, height, color_type)
+        .expect("Failed to encode png!");
+    let data = base64(&png);
+    // quietly, so no answers turn up as key presses
+    let mut encoded = "\x1b_Ga=d,q=2\x1b\\".to_string();
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        if i == 0 {
+            encoded.push_str(&format!("\x1b_Ga=T,f=100,C=1,q=2,m={};", more));
+        } else {
+            encoded.push_str(&format!("\x1b_Gm={};", more));
+        }
+        encoded.push_str(std::str::from_utf8(chunk).unwrap());
+        encoded.push_str("\x1b\\");
+    }
+    return encoded;
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i)) & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    return encoded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_read_from_the_reply() {
+        let kitty = b"\x1b_Gi=31;OK\x1b\\\x1b[4;1200;1920t\x1b[?62;22c";
+        assert!(Answers::is_complete(kitty));
+        assert_eq!(Answers::parse(kitty), Answers { kitty: true, sixel: false, pixels: Some((1920, 1200)) });
+        let sixel = b"\x1b[?63;1;2;4;6;9;15;22c";
+        assert_eq!(Answers::parse(sixel), Answers { kitty: false, sixel: true, pixels: None });
+        let refused = b"\x1b_Gi=31;ENOTSUPPORTED:no\x1b\\\x1b[4;0;0t\x1b[?1;2c";
+        assert_eq!(Answers::parse(refused), Answers::default());
+    }
+
+    #[test]
+    fn answers_are_in_once_the_attributes_are() {
+        assert!(!Answers::is_complete(b""));
+        assert!(!Answers::is_complete(b"\x1b_Gi=31;OK\x1b\\\x1b[4;1200;1920t"));
+        assert!(!Answers::is_complete(b"\x1b[?62;4"));
+        assert!(Answers::is_complete(b"\x1b[?62;4c"));
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use bytes::BufMut;
+
+use crate::frame::{FRAME_VERSION, SEGMENT_OVERHEAD};
+
+pub const PROTOCOL_VERSION: u8 = FRAME_VERSION;
+
+pub const FEATURE_WINDOW: u32 = 0x0001;
+pub const FEATURE_DEFLATE: u32 = 0x0002;
+pub const FEATURE_ZSTD: u32 = 0x0004;
+/// Reads Data Matrix codes
+pub const FEATURE_DATA_MATRIX: u32 = 0x0008;
+/// Reads Aztec codes
+pub const FEATURE_AZTEC: u32 = 0x0010;
+/// Encrypting with a passphrase, which both sides have to be doing
+pub const FEATURE_ENCRYPTION: u32 = 0x0020;
+/// Reads codes laid over each other in red, green and blue
+pub const FEATURE_RGB: u32 = 0x0040;
+
+/// What this build can do
+pub const SUPPORTED_FEATURES: u32 = FEATURE_WINDOW | FEATURE_DEFLATE | FEATURE_ZSTD;
+
+/// Nothing smaller than this is worth sending
+pub const MIN_FRAGMENT_SIZE: u16 = 16;
+
+/// version, features, max payload, fragment size, window size
+pub const CAPABILITIES_SIZE: usize = 1 + 4 + 2 + 2 + 2;
+
+///
+/// How the data is squeezed before it's cut into segments.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    pub fn feature(&self) -> u32 {
+        return match self {
+            Compression::None => 0,
+            Compression::Deflate => FEATURE_DEFLATE,
+            Compression::Zstd => FEATURE_ZSTD,
+        };
+    }
+
+    ///
+    /// The compression to use given what we'd like and the features both sides agreed on.
+    /// Any compression beats none so we fall back to whichever the peer has.
+    ///
+    pub fn choose(preferred: Compression, features: u32) -> Compression {
+        if preferred == Compression::None || features & preferred.feature() != 0 {
+            return preferred;
+        }
+        return [Compression::Zstd, Compression::Deflate].into_iter()
+            .find(|compression| features & compression.feature() != 0)
+            .unwrap_or(Compression::None);
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        return f.write_str(match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+        });
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("{} isn't one of none, deflate or zstd", s)),
+        };
+    }
+}
+
+///
+/// What one side of a transfer can do and would like to do, exchanged in HELLO frames before
+/// any data.  The HELLO-ACK carries what both sides settled on.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u8,
+    pub features: u32,
+    pub max_payload: u16,
+    pub fragment_size: u16,
+    pub window_size: u16,
+}
+
+impl Capabilities {
+    ///
+    /// Everything this build supports, less compression if we'd rather not.
+    ///
+    pub fn new(max_payload: u16, fragment_size: u16, window_size: u16, compression: Compression) -> Self {
+        let features = if compression == Compression::None {
+            SUPPORTED_FEATURES & !(FEATURE_DEFLATE | FEATURE_ZSTD)
+        } else {
+            SUPPORTED_FEATURES
+        };
+        return Self {
+            version: PROTOCOL_VERSION,
+            features,
+            max_payload,
+            fragment_size,
+            window_size,
+        }
+    }
+
+    pub fn decode(encoded: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_be_bytes(encoded[i..i + 2].try_into().unwrap());
+        return Self {
+            version: encoded[0],
+            features: u32::from_be_bytes(encoded[1..5].try_into().unwrap()),
+            max_payload: u16_at(5),
+            fragment_size: u16_at(7),
+            window_size: u16_at(9),
+        }
+    }
+
+    pub fn encode(&self, encoded: &mut Vec<u8>) {
+        encoded.put_u8(self.version);
+        encoded.put_u32(self.features);
+        encoded.put_u16(self.max_payload);
+        encoded.put_u16(self.fragment_size);
+        encoded.put_u16(self.window_size);
+    }
+
+    fn largest_fragment(&self) -> u16 {
+        return self.fragment_size.min(self.max_payload.saturating_sub(SEGMENT_OVERHEAD as u16));
+    }
+
+    ///
+    /// Settle on the settings both sides can live with, or explain why there aren't any.
+    ///
+    pub fn negotiate(&self, peer: &Capabilities) -> Result<Capabilities, String> {
+        if self.version != peer.version {
+            return Err(format!("Peer speaks protocol v{} but we speak v{}", peer.version, self.version));
+        }
+        if (self.features ^ peer.features) & FEATURE_ENCRYPTION != 0 {
+            return Err("Only one side has a passphrase, both need the same --passphrase".to_string());
+        }
+        let features = self.features & peer.features;
+        if features & FEATURE_WINDOW == 0 {
+            return Err("Peer doesn't support windowed transfers, try --broadcast".to_string());
+        }
+        let fragment_size = self.largest_fragment().min(peer.largest_fragment());
+        if fragment_size < MIN_FRAGMENT_SIZE {
+            return Err(format!("Fragments would be {}b, check --max-payload and --fragment-size on both sides", fragment_size));
+        }
+        let window_size = self.window_size.min(peer.window_size);
+        if window_size == 0 {
+            return Err("Window size must be at least 1".to_string());
+        }
+        return Ok(Capabilities {
+            version: self.version,
+            features,
+            max_payload: self.max_payload.min(peer.max_payload),
+            fragment_size,
+            window_size,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ours() -> Capabilities {
+        return Capabilities::new(500, 400, 8, Compression::Zstd);
+    }
+
+    #[test]
+    fn capabilities_survive_a_round_trip() {
+        let mut encoded = Vec::new();
+        ours().encode(&mut encoded);
+        assert_eq!(encoded.len(), CAPABILITIES_SIZE);
+        assert_eq!(Capabilities::decode(&encoded), ours());
+    }
+
+    #[test]
+    fn both_sides_settle_on_the_least_of_each() {
+        let peer = Capabilities {
+            features: FEATURE_WINDOW | FEATURE_DEFLATE,
+            max_payload: 300,
+            window_size: 4,
+            ..ours()
+        };
+        let agreed = ours().negotiate(&peer).unwrap();
+        assert_eq!(agreed, Capabilities {
+            version: PROTOCOL_VERSION,
+            features: FEATURE_WINDOW | FEATURE_DEFLATE,
+            max_payload: 300,
+            fragment_size: 300 - SEGMENT_OVERHEAD as u16,
+            window_size: 4,
+        });
+        assert_eq!(peer.negotiate(&ours()), Ok(agreed));
+        assert_eq!(Compression::choose(Compression::Zstd, agreed.features), Compression::Deflate);
+    }
+
+    #[test]
+    fn mismatches_are_explained() {
+        let newer = Capabilities { version: PROTOCOL_VERSION + 1, ..ours() };
+        assert!(ours().negotiate(&newer).is_err());
+        let broadcast_only = Capabilities { features: FEATURE_ZSTD, ..ours() };
+        assert!(ours().negotiate(&broadcast_only).is_err());
+        let tiny = Capabilities { fragment_size: MIN_FRAGMENT_SIZE - 1, ..ours() };
+        assert!(ours().negotiate(&tiny).is_err());
+        let no_window = Capabilities { window_size: 0, ..ours() };
+        assert!(ours().negotiate(&no_window).is_err());
+    }
+
+    #[test]
+    fn both_sides_encrypt_or_neither_does() {
+        let encrypting = Capabilities { features: ours().features | FEATURE_ENCRYPTION, ..ours() };
+        assert!(ours().negotiate(&encrypting).is_err());
+        assert!(encrypting.negotiate(&ours()).is_err());
+        assert_ne!(encrypting.negotiate(&encrypting).unwrap().features & FEATURE_ENCRYPTION, 0);
+    }
+}
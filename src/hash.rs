@@ -0,0 +1,59 @@
+use std::io::{Result, Write};
+
+use sha2::{Digest, Sha256};
+
+pub const HASH_SIZE: usize = 32;
+
+/// A SHA-256 of everything that was sent
+pub type Hash = [u8; HASH_SIZE];
+
+///
+/// Hashes content as it streams past.
+///
+#[derive(Clone, Default)]
+pub struct ContentHasher {
+    hasher: Sha256,
+}
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    ///
+    /// The hash of everything seen so far.  The hasher starts over afterwards.
+    ///
+    pub fn finish(&mut self) -> Hash {
+        return self.hasher.finalize_reset().into();
+    }
+}
+
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.update(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+pub fn to_hex(hash: &Hash) -> String {
+    return hash.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+pub fn parse_hex(hex: &str) -> std::result::Result<Hash, String> {
+    if hex.len() != HASH_SIZE * 2 || !hex.is_ascii() {
+        return Err(format!("expected {} hex digits", HASH_SIZE * 2));
+    }
+    let mut hash = [0u8; HASH_SIZE];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|err| err.to_string())?;
+    }
+    return Ok(hash);
+}
@@ -0,0 +1,95 @@
+use bytes::BufMut;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand::RngCore;
+
+use crate::hash::Hash;
+
+pub const IDENTITY_KEY_SIZE: usize = 32;
+/// public key, signature
+pub const SIGNATURE_SIZE: usize = IDENTITY_KEY_SIZE + 64;
+
+/// Keeps these signatures from being mistaken for anything else the key signs
+const SIGNATURE_CONTEXT: &[u8] = b"piccp content";
+
+pub type IdentityKey = [u8; IDENTITY_KEY_SIZE];
+
+///
+/// A sender's Ed25519 signature over the hash of everything it sent, along with the key
+/// that made it.
+///
+/// ```text
+/// | public key [u8; 32] | signature [u8; 64] |
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub key: IdentityKey,
+    pub signature: [u8; 64],
+}
+
+impl Signature {
+    pub fn decode(encoded: &[u8]) -> Option<Self> {
+        let encoded = encoded.get(0..SIGNATURE_SIZE)?;
+        return Some(Self {
+            key: encoded[0..IDENTITY_KEY_SIZE].try_into().unwrap(),
+            signature: encoded[IDENTITY_KEY_SIZE..].try_into().unwrap(),
+        });
+    }
+
+    pub fn encode(&self, encoded: &mut Vec<u8>) {
+        encoded.put_slice(&self.key);
+        encoded.put_slice(&self.signature);
+    }
+
+    ///
+    /// Did the key sign this hash?
+    ///
+    pub fn verify(&self, hash: &Hash) -> bool {
+        let signature = ed25519_dalek::Signature::from_bytes(&self.signature);
+        return VerifyingKey::from_bytes(&self.key)
+            .is_ok_and(|key| key.verify_strict(&Self::message(hash), &signature).is_ok());
+    }
+
+    fn message(hash: &Hash) -> Vec<u8> {
+        let mut message = SIGNATURE_CONTEXT.to_vec();
+        message.extend_from_slice(hash);
+        return message;
+    }
+}
+
+///
+/// Our own signing key.
+///
+#[derive(Debug, Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        return Self::from_bytes(&secret);
+    }
+
+    pub fn from_bytes(secret: &[u8; 32]) -> Self {
+        return Self {
+            key: SigningKey::from_bytes(secret),
+        };
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        return self.key.to_bytes();
+    }
+
+    pub fn public_key(&self) -> IdentityKey {
+        return self.key.verifying_key().to_bytes();
+    }
+
+    pub fn sign(&self, hash: &Hash) -> Signature {
+        return Signature {
+            key: self.public_key(),
+            signature: self.key.sign(&Signature::message(hash)).to_bytes(),
+        };
+    }
+}
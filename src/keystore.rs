@@ -0,0 +1,214 @@
+use std::env::var_os;
+use std::fs::{create_dir_all, OpenOptions, read_to_string};
+use std::io::{Error, ErrorKind, Result, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use crate::hash::{Hash, parse_hex, to_hex};
+use crate::identity::{Identity, IdentityKey, Signature};
+use crate::reason::Reason;
+
+const IDENTITY_FILE: &str = "identity";
+const TRUSTED_FILE: &str = "trusted";
+
+///
+/// Where our identity and the keys of the peers we trust are kept.  Both are plain text:
+/// `identity` holds our secret key in hex and `trusted` has a line per peer with their public
+/// key in hex and the name it was trusted under.
+///
+pub struct Keystore {
+    dir: PathBuf,
+    trust_on_first_use: bool,
+    require_signature: bool,
+    allow_unsigned: bool,
+}
+
+impl Keystore {
+    ///
+    /// The keystore in `dir`, or `.piccp` in the home directory.  With `trust_on_first_use`
+    /// a key we've not seen before is trusted the first time it signs something.  Anything
+    /// unsigned is refused with `require_signature`, or once we trust someone, unless we're
+    /// told to `allow_unsigned`.
+    ///
+    pub fn open(dir: &str, trust_on_first_use: bool, require_signature: bool, allow_unsigned: bool) -> Self {
+        let dir = if dir.is_empty() {
+            var_os("HOME").or_else(|| var_os("USERPROFILE"))
+                .map_or(PathBuf::from("."), PathBuf::from)
+                .join(".piccp")
+        } else {
+            PathBuf::from(dir)
+        };
+        return Self {
+            dir,
+            trust_on_first_use,
+            require_signature,
+            allow_unsigned,
+        };
+    }
+
+    ///
+    /// Make a new identity, refusing to replace the one we have unless told to.
+    ///
+    pub fn generate(&self, force: bool) -> Result<Identity> {
+        create_dir_all(&self.dir)?;
+        let identity = Identity::generate();
+        let mut options = OpenOptions::new();
+        options.write(true);
+        if force {
+            options.create(true).truncate(true);
+        } else {
+            options.create_new(true);
+        }
+        #[cfg(unix)]
+        options.mode(0o600);
+        let path = self.dir.join(IDENTITY_FILE);
+        let mut file = options.open(&path).map_err(|err| match err.kind() {
+            ErrorKind::AlreadyExists => Error::new(err.kind(), format!("{} already exists, --force replaces it", path.display())),
+            _ => err,
+        })?;
+        writeln!(file, "{}", to_hex(&identity.to_bytes()))?;
+        return Ok(identity);
+    }
+
+    ///
+    /// Our identity, if we've made one.
+    ///
+    pub fn identity(&self) -> Result<Option<Identity>> {
+        let path = self.dir.join(IDENTITY_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let secret = parse_hex(read_to_string(&path)?.trim())
+            .map_err(|err| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), err)))?;
+        return Ok(Some(Identity::from_bytes(&secret)));
+    }
+
+    pub fn trusted(&self) -> Result<Vec<(IdentityKey, String)>> {
+        let path = self.dir.join(TRUSTED_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut trusted = Vec::new();
+        for line in read_to_string(&path)?.lines() {
+            let (key, name) = line.split_once(' ').unwrap_or((line, ""));
+            if let Ok(key) = parse_hex(key) {
+                trusted.push((key, name.to_string()));
+            }
+        }
+        return Ok(trusted);
+    }
+
+    ///
+    /// Trust `key` under `name`, which has to fit on its line of the file.
+    ///
+    pub fn trust(&self, key: &IdentityKey, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{:?} isn't a name, it can't be empty or have spaces", name)));
+        }
+        create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new().append(true).create(true).open(self.dir.join(TRUSTED_FILE))?;
+        writeln!(file, "{} {}", to_hex(key), name)?;
+        return Ok(());
+    }
+
+    ///
+    /// Who sent what we wrote, as far as we can tell.  A bad signature, or one from a key we
+    /// don't trust, is why the transfer failed.
+    ///
+    pub fn check(&self, signature: Option<Signature>, written: &Hash) -> std::result::Result<String, (Reason, String)> {
+        let trusted = self.trusted()
+            .map_err(|err| (Reason::Io, format!("Couldn't read the trusted keys: {}", err)))?;
+        let signature = match signature {
+            Some(signature) => signature,
+            // anyone can take a signature off
+            None if !self.allow_unsigned && (self.require_signature || !trusted.is_empty()) => {
+                return Err((Reason::Untrusted, "Unsigned, --allow-unsigned would accept it".to_string()));
+            }
+            None => return Ok("unsigned".to_string()),
+        };
+        if !signature.verify(written) {
+            return Err((Reason::BadSignature, format!("Bad signature from {}", to_hex(&signature.key))));
+        }
+        if let Some((_, name)) = trusted.iter().find(|(key, _)| *key == signature.key) {
+            return Ok(format!("signed by {}", name));
+        }
+        if !self.trust_on_first_use {
+            return Err((Reason::Untrusted, format!("Signed by untrusted key {}, --trust-on-first-use would trust it", to_hex(&signature.key))));
+        }
+        return match self.trust(&signature.key, "first-use") {
+            Ok(_) => Ok(format!("signed by new key {}, now trusted", to_hex(&signature.key))),
+            Err(err) => Ok(format!("signed by new key {} but couldn't trust it: {}", to_hex(&signature.key), err)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+
+    use super::*;
+
+    const WRITTEN: Hash = [3; 32];
+
+    ///
+    /// A keystore of our own under the system's temporary directory.
+    ///
+    fn keystore(name: &str, trust_on_first_use: bool, require_signature: bool, allow_unsigned: bool) -> Keystore {
+        let dir = temp_dir().join(format!("piccp-keystore-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        return Keystore::open(dir.to_str().unwrap(), trust_on_first_use, require_signature, allow_unsigned);
+    }
+
+    #[test]
+    fn unsigned_is_only_accepted_until_someone_is_trusted() {
+        let keystore = keystore("unsigned", false, false, false);
+        assert_eq!(keystore.check(None, &WRITTEN), Ok("unsigned".to_string()));
+        keystore.trust(&Identity::generate().public_key(), "alice").unwrap();
+        assert_eq!(keystore.check(None, &WRITTEN).unwrap_err().0, Reason::Untrusted);
+        remove_dir_all(&keystore.dir).unwrap();
+    }
+
+    #[test]
+    fn unsigned_is_refused_when_a_signature_is_required() {
+        assert_eq!(keystore("required", false, true, false).check(None, &WRITTEN).unwrap_err().0, Reason::Untrusted);
+        let allowing = keystore("allowed", false, false, true);
+        allowing.trust(&Identity::generate().public_key(), "alice").unwrap();
+        assert_eq!(allowing.check(None, &WRITTEN), Ok("unsigned".to_string()));
+        remove_dir_all(&allowing.dir).unwrap();
+    }
+
+    #[test]
+    fn a_signature_over_something_else_is_bad() {
+        let keystore = keystore("bad", false, false, false);
+        let identity = Identity::generate();
+        keystore.trust(&identity.public_key(), "alice").unwrap();
+        let signature = identity.sign(&[4; 32]);
+        assert_eq!(keystore.check(Some(signature), &WRITTEN).unwrap_err().0, Reason::BadSignature);
+        remove_dir_all(&keystore.dir).unwrap();
+    }
+
+    #[test]
+    fn strangers_are_untrusted_unless_trusted_on_first_use() {
+        let identity = Identity::generate();
+        let cautious = keystore("cautious", false, false, false);
+        assert_eq!(cautious.check(Some(identity.sign(&WRITTEN)), &WRITTEN).unwrap_err().0, Reason::Untrusted);
+        let trusting = keystore("trusting", true, false, false);
+        assert!(trusting.check(Some(identity.sign(&WRITTEN)), &WRITTEN).unwrap().ends_with("now trusted"));
+        assert_eq!(trusting.check(Some(identity.sign(&WRITTEN)), &WRITTEN), Ok("signed by first-use".to_string()));
+        remove_dir_all(&trusting.dir).unwrap();
+    }
+
+    #[test]
+    fn names_have_to_fit_on_their_line() {
+        let keystore = keystore("names", false, false, false);
+        let key = Identity::generate().public_key();
+        for name in ["", "two words", "new\nline", "tab\t"] {
+            assert_eq!(keystore.trust(&key, name).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+        keystore.trust(&key, "alice").unwrap();
+        assert_eq!(keystore.trusted().unwrap(), vec![(key, "alice".to_string())]);
+        remove_dir_all(&keystore.dir).unwrap();
+    }
+}
@@ -1,19 +1,25 @@
-use std::fs::{File, metadata};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, stderr, Stderr, stdin, stdout, Write};
+use std::fs::File;
+use std::io::{copy, Error, ErrorKind, Read, Result, Seek, SeekFrom, stderr, Stderr, stdin};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
 
 use clap::Parser;
 use crossterm::{
+    cursor::{MoveTo, RestorePosition, SavePosition},
     event::{DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    style::Print,
+    terminal::{self, disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use crossterm::event::EventStream;
 use futures::StreamExt;
 use tokio::select;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::{Instant, sleep_until};
 use tui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     Terminal,
     widgets::{Block, Borders}
 };
@@ -22,29 +28,68 @@ use tui::style::{Color, Modifier, Style};
 use tui::text::{Span, Text};
 use tui::widgets::{Gauge, Paragraph};
 
-use crate::args::Args;
+use crate::args::{Args, Command};
 use crate::camera::Camera;
-use crate::codec::{Decoder, Encoder};
-use crate::frame::Frame;
+use crate::capacity::{Capacity, Tiling};
+use crate::codec::{Decoder, Encoder, Rendering};
+use crate::frame::{Frame, FRAME_TYPE_DONE, FRAME_TYPE_METADATA, ParsedFrame};
+use crate::handshake::{Compression, MIN_FRAGMENT_SIZE};
+use crate::hash::{ContentHasher, Hash, to_hex};
+use crate::keystore::Keystore;
 use crate::log::Log;
+use crate::manifest::Manifest;
 use crate::message::Message;
-use crate::transport::{SegmentSource, SegmentSourceFactory, Transport};
+use crate::metadata::FileMetadata;
+use crate::output::Output;
+use crate::reason::Reason;
+use crate::resume::ResumeState;
+use crate::session::Session;
+use crate::symbology::CodeKind;
+use crate::transport::{SegmentSource, SegmentSourceFactory, Transport, TransportOptions};
 
 mod args;
 mod transport;
 mod frame;
 mod message;
 mod camera;
+mod capacity;
+mod cipher;
 mod codec;
+mod compression;
+mod crosstalk;
+mod fountain;
+mod graphics;
+mod handshake;
+mod hash;
+mod identity;
+mod keystore;
 mod log;
+mod manifest;
+mod metadata;
+mod output;
+mod pairing;
+mod reason;
+mod resume;
+mod session;
+mod symbology;
+mod timer;
 
 
 #[derive(Debug, Clone)]
 struct UiState {
     block_text: String,
+    file_name: String,
     segment_offset: usize,
     segment_count: usize,
     message: String,
+    /// What the other screen should be showing too
+    pairing_code: Option<String>,
+    paired: bool,
+    /// The transfer is over, leaving doesn't cancel anything
+    finished: bool,
+    /// We've stopped and are showing the peer why for a moment
+    stopping: bool,
+    exit_status: i32,
     done: bool,
 }
 
@@ -52,56 +97,86 @@ impl UiState {
     fn new() -> Self {
         return Self {
             block_text: "".to_string(),
+            file_name: "".to_string(),
             message: "".to_string(),
             segment_offset: 0,
             segment_count: 0,
+            pairing_code: None,
+            paired: false,
+            finished: false,
+            stopping: false,
+            exit_status: 0,
             done: false
         }
     }
 }
 
 struct StdinSource {
-    next_offset: usize
+    segments: Vec<Vec<u8>>
 }
 impl SegmentSource for StdinSource {
     fn read_segment(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        if offset != self.next_offset {
+        // stdin can only be read once so keep what we've read around for retransmission
+        if offset < self.segments.len() {
+            let segment = &self.segments[offset];
+            buf[0..segment.len()].copy_from_slice(segment);
+            return Ok(segment.len());
+        }
+        if offset != self.segments.len() {
             return Result::Err(Error::from(ErrorKind::InvalidData));
         }
         let stdin = stdin();
         let mut lock = stdin.lock();
-        let result = lock.read(buf);
-        self.next_offset += 1;
-        return result;
+        let mut size = 0;
+        while size < buf.len() {
+            match lock.read(&mut buf[size..])? {
+                0 => break,
+                n => size += n,
+            }
+        }
+        if size > 0 {
+            self.segments.push(buf[0..size].to_vec());
+        }
+        return Ok(size);
     }
 }
 
 struct StdinSourceFactory;
 impl SegmentSourceFactory for StdinSourceFactory {
     type SegmentSourceType = StdinSource;
-    fn create_segment_source(&self) -> Self::SegmentSourceType {
-        return StdinSource {next_offset: 0};
+    fn create_segment_source(&self) -> Result<Self::SegmentSourceType> {
+        return Ok(StdinSource {segments: Vec::new()});
     }
 }
 
 struct FileSource {
     file: File,
-    size: usize
+    metadata: FileMetadata
 }
 impl FileSource {
-    fn new(path: String) -> Self {
-        return Self {
-            file: File::open(path.clone()).unwrap(),
-            size: metadata(path).unwrap().len() as usize
-        };
+    fn new(path: String) -> Result<Self> {
+        let mut file = File::open(path.clone())?;
+        // hashed up front so the receiver can tell it's the same file if we're interrupted
+        let mut hasher = ContentHasher::new();
+        copy(&mut file, &mut hasher)?;
+        return Ok(Self {
+            metadata: FileMetadata {
+                hash: Some(hasher.finish()),
+                ..FileMetadata::from_path(&path, &file.metadata()?)
+            },
+            file,
+        });
     }
 }
 impl SegmentSource for FileSource {
     fn size(&self) -> Option<usize> {
-        return Some(self.size);
+        return self.metadata.size.map(|size| size as usize);
+    }
+    fn metadata(&self) -> FileMetadata {
+        return self.metadata.clone();
     }
     fn read_segment(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        self.file.seek(SeekFrom::Start((offset * buf.len()) as u64)).unwrap();
+        self.file.seek(SeekFrom::Start((offset * buf.len()) as u64))?;
         return self.file.read(buf);
     }
 }
@@ -111,13 +186,100 @@ struct FileSourceFactory {
 }
 impl SegmentSourceFactory for FileSourceFactory {
     type SegmentSourceType = FileSource;
-    fn create_segment_source(&self) -> Self::SegmentSourceType {
+    fn create_segment_source(&self) -> Result<Self::SegmentSourceType> {
         return FileSource::new(self.path.clone());
     }
 }
 
 
-async fn next_message(ui_state: UiState, encoder: &Encoder, rx: &mut UnboundedReceiver<Message>) -> UiState {
+///
+/// What the receiver tells the user about the hash of what it wrote.
+///
+fn verification_report(written: Hash, sent: Option<Hash>, expected: Option<Hash>) -> std::result::Result<String, String> {
+    if let Some(sent) = sent.filter(|sent| *sent != written) {
+        return Err(format!("Hash mismatch! The sender has {} but we wrote {}", to_hex(&sent), to_hex(&written)));
+    }
+    if let Some(expected) = expected.filter(|expected| *expected != written) {
+        return Err(format!("Hash mismatch! Expected {} but we wrote {}", to_hex(&expected), to_hex(&written)));
+    }
+    if sent.is_none() && expected.is_none() {
+        return Ok(format!("Done, unverified sha256 {}", to_hex(&written)));
+    }
+    return Ok(format!("Verified sha256 {}", to_hex(&written)));
+}
+///
+/// Several files sent as one: the encoded manifest followed by each file in turn.
+///
+struct TreeSource {
+    manifest: Vec<u8>,
+    files: Vec<(PathBuf, u64)>,
+    metadata: FileMetadata
+}
+impl SegmentSource for TreeSource {
+    fn size(&self) -> Option<usize> {
+        return self.metadata.size.map(|size| size as usize);
+    }
+    fn metadata(&self) -> FileMetadata {
+        return self.metadata.clone();
+    }
+    fn read_segment(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut position = (offset * buf.len()) as u64;
+        let mut size = 0;
+        if position < self.manifest.len() as u64 {
+            let head = &self.manifest[position as usize..];
+            size = head.len().min(buf.len());
+            buf[0..size].copy_from_slice(&head[0..size]);
+            position += size as u64;
+        }
+        let mut start = self.manifest.len() as u64;
+        for (path, len) in &self.files {
+            let end = start + len;
+            if size < buf.len() && position >= start && position < end {
+                let want = (buf.len() - size).min((end - position) as usize);
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(position - start))?;
+                file.read_exact(&mut buf[size..size + want])?;
+                size += want;
+                position += want as u64;
+            }
+            start = end;
+        }
+        return Ok(size);
+    }
+}
+
+struct TreeSourceFactory {
+    manifest: Manifest,
+    sources: Vec<PathBuf>,
+    name: String
+}
+impl SegmentSourceFactory for TreeSourceFactory {
+    type SegmentSourceType = TreeSource;
+    fn create_segment_source(&self) -> Result<Self::SegmentSourceType> {
+        let mut manifest = Vec::with_capacity(self.manifest.encoded_size());
+        self.manifest.encode(&mut manifest);
+        return Ok(TreeSource {
+            manifest,
+            files: self.sources.iter().cloned()
+                .zip(self.manifest.entries.iter().map(|entry| entry.size))
+                .collect(),
+            metadata: FileMetadata {
+                name: self.name.clone(),
+                size: Some(self.manifest.total_size()),
+                manifest: true,
+                ..FileMetadata::default()
+            }
+        });
+    }
+}
+
+async fn next_message(ui_state: UiState,
+                      encoder: &mut Encoder,
+                      output: &mut Output,
+                      transport: &Transport,
+                      expect_hash: Option<Hash>,
+                      keystore: &Keystore,
+                      rx: &mut UnboundedReceiver<Message>) -> UiState {
     return if let Some(message) = rx.recv().await {
         match message {
             Message::Log(log) => {
@@ -126,37 +288,265 @@ async fn next_message(ui_state: UiState, encoder: &Encoder, rx: &mut UnboundedRe
                     ..ui_state
                 }
             },
+            // nothing replaces the reason we stopped
+            Message::WriteData(_) | Message::WriteLanes(_) if ui_state.stopping => {
+                ui_state
+            },
+            Message::WriteLanes(frames) => {
+                let block_text = encoder.encode_lanes(&frames);
+                let mut file_name = ui_state.file_name.clone();
+                let mut segments = Vec::new();
+                for frame in &frames {
+                    match frame.parsed() {
+                        ParsedFrame::Segment { segment_offset, segment_count, .. } => segments.push((segment_offset, segment_count)),
+                        ParsedFrame::Metadata(metadata) => file_name = metadata.name,
+                        _ => {}
+                    }
+                }
+                match segments.last() {
+                    Some(&(segment_offset, segment_count)) => {
+                        let offsets: Vec<String> = segments.iter().map(|(offset, _)| format!("#{}", offset)).collect();
+                        UiState {
+                            block_text,
+                            file_name,
+                            segment_offset,
+                            segment_count,
+                            message: format!("Sending segments {}", offsets.join(", ")),
+                            ..ui_state
+                        }
+                    }
+                    None => {
+                        UiState {
+                            block_text,
+                            file_name,
+                            message: format!("Sending {} symbols", frames.len()),
+                            ..ui_state
+                        }
+                    }
+                }
+            },
             Message::WriteData(frame) => {
-                if frame.is_segment() {
-                    UiState {
-                        block_text: encoder.encode(&frame),
-                        segment_offset: frame.get_segment_offset(),
-                        segment_count: frame.get_segment_count(),
-                        message: format!("Sending {}b segment #{}", frame.get_data().len(), frame.get_segment_offset()),
-                        ..ui_state
+                let block_text = encoder.encode(&frame);
+                match frame.parsed() {
+                    ParsedFrame::Segment { segment_offset, segment_count, data } => {
+                        UiState {
+                            block_text,
+                            segment_offset,
+                            segment_count,
+                            message: format!("Sending {}b segment #{}", data.len(), segment_offset),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Symbol { symbol, .. } => {
+                        UiState {
+                            block_text,
+                            message: format!("Sending {}b symbol", symbol.len()),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Cts { segment_offset, .. } => {
+                        UiState {
+                            block_text,
+                            message: format!("Clear to send from segment #{}", segment_offset),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Resume { segment_offset, .. } => {
+                        UiState {
+                            block_text,
+                            message: format!("Resume from segment #{}", segment_offset),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Hello(capabilities, _) => {
+                        UiState {
+                            block_text,
+                            message: format!("Hello, protocol v{}", capabilities.version),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::HelloAck(capabilities, _) => {
+                        UiState {
+                            block_text,
+                            message: format!("Agreed on {}b fragments, window of {}", capabilities.fragment_size, capabilities.window_size),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Metadata(metadata) => {
+                        UiState {
+                            block_text,
+                            file_name: metadata.name,
+                            message: "Sending metadata".to_string(),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Sealed { frame_type: FRAME_TYPE_METADATA, .. } => {
+                        UiState {
+                            block_text,
+                            message: "Sending sealed metadata".to_string(),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Done(..) | ParsedFrame::Sealed { frame_type: FRAME_TYPE_DONE, .. } => {
+                        UiState {
+                            block_text,
+                            message: "Done, waiting for the receiver to verify".to_string(),
+                            ..ui_state
+                        }
+                    }
+                    ParsedFrame::Verified(_) | ParsedFrame::Key(_) | ParsedFrame::Abort(..) | ParsedFrame::Error(..) |
+                    ParsedFrame::Sealed { .. } => {
+                        UiState {
+                            block_text,
+                            ..ui_state
+                        }
                     }
-                } else if frame.is_cts() {
+                }
+            },
+            Message::Symbology(symbology) => match encoder.switch(symbology) {
+                Ok(()) => ui_state,
+                Err(err) => UiState {
+                    message: err,
+                    ..ui_state
+                },
+            },
+            Message::Colour(colour) => {
+                encoder.colour(colour);
+                ui_state
+            },
+            Message::Metadata(metadata) => {
+                let mut size = match metadata.size {
+                    Some(size) => format!("{} bytes", size),
+                    None => "unknown size".to_string(),
+                };
+                if let Some(mime_type) = &metadata.mime_type {
+                    size = format!("{}, {}", size, mime_type);
+                }
+                if metadata.compression != Compression::None {
+                    size = format!("{}, {}", size, metadata.compression);
+                }
+                if metadata.encrypted {
+                    size = format!("{}, encrypted", size);
+                }
+                let name = if metadata.name.is_empty() && metadata.manifest {
+                    "several files".to_string()
+                } else {
+                    metadata.name.clone()
+                };
+                let message = match output.prepare(&metadata) {
+                    Ok(_) => format!("Receiving {} ({})", name, size),
+                    Err(err) => stop(transport, &err, format!("Failed to prepare output for {}: {}", metadata.name, err)),
+                };
+                UiState {
+                    message,
+                    file_name: metadata.name,
+                    ..ui_state
+                }
+            },
+            Message::Resume(state) => {
+                output.resume(state);
+                ui_state
+            },
+            Message::Pairing(code) => {
+                UiState {
+                    message: format!("Pairing code {}, check the other screen", code),
+                    pairing_code: Some(code),
+                    ..ui_state
+                }
+            },
+            Message::Verify(sent, signature) => {
+                let message = match output.finish() {
+                    Ok(written) => match verification_report(written, sent, expect_hash) {
+                        Ok(report) => {
+                            let damaged = output.damaged();
+                            if !damaged.is_empty() {
+                                let damaged = format!("Damaged: {}", damaged.join(", "));
+                                transport.stop(Reason::HashMismatch, damaged.clone());
+                                damaged
+                            } else {
+                                match keystore.check(signature, &written) {
+                                    Ok(signed) => {
+                                        transport.verified(written);
+                                        format!("{}, {}", report, signed)
+                                    }
+                                    Err((reason, text)) => {
+                                        transport.stop(reason, text.clone());
+                                        text
+                                    }
+                                }
+                            }
+                        }
+                        Err(mismatch) => {
+                            transport.stop(Reason::HashMismatch, mismatch.clone());
+                            mismatch
+                        }
+                    },
+                    Err(err) => stop(transport, &err, format!("Failed to finish output: {}", err)),
+                };
+                UiState {
+                    message,
+                    ..ui_state
+                }
+            },
+            Message::AppendToOutput(frame) => {
+                if let ParsedFrame::Segment { segment_offset, segment_count, data } = frame.parsed() {
+                    let message = match output.append(data) {
+                        Ok(_) => format!("Append {} bytes", data.len()),
+                        Err(err) => stop(transport, &err, format!("Failed to write: {}", err)),
+                    };
                     UiState {
-                        block_text: encoder.encode(&frame),
-                        message: format!("Clear to send segment #{}", frame.get_segment_offset()),
+                        segment_offset,
+                        segment_count,
+                        message,
                         ..ui_state
                     }
                 } else {
+                    ui_state
+                }
+            },
+            Message::Progress(received, needed) => {
+                UiState {
+                    segment_offset: received - 1,
+                    segment_count: needed,
+                    message: format!("Collected {} of {} symbols", received, needed),
+                    ..ui_state
+                }
+            },
+            Message::WriteToOutput(frame, received) => {
+                if let ParsedFrame::Segment { segment_offset, segment_count, data } = frame.parsed() {
+                    let message = match output.write_segment(segment_offset, data) {
+                        Ok(_) => format!("Write {} bytes to segment #{}", data.len(), segment_offset),
+                        Err(err) => stop(transport, &err, format!("Failed to write segment #{}: {}", segment_offset, err)),
+                    };
                     UiState {
-                        block_text: encoder.encode(&frame),
-                        message: "Done".to_string(),
+                        segment_offset: received - 1,
+                        segment_count,
+                        message,
                         ..ui_state
                     }
+                } else {
+                    ui_state
                 }
             },
-            Message::AppendToOutput(frame) => {
-                let mut lock = stdout().lock();
-                let data = frame.get_data();
-                lock.write(data).unwrap();
+            Message::Failed(reason, text) if !ui_state.stopping => {
+                UiState {
+                    message: text,
+                    stopping: true,
+                    exit_status: reason.exit_status(),
+                    ..ui_state
+                }
+            },
+            Message::Stopped(reason, text) => {
                 UiState {
-                    segment_offset: frame.get_segment_offset(),
-                    segment_count: frame.get_segment_count(),
-                    message: format!("Append {} bytes", data.len()),
+                    message: format!("The other side stopped, {}: {}", reason, text),
+                    exit_status: if ui_state.stopping { ui_state.exit_status } else { reason.exit_status() },
+                    done: true,
+                    ..ui_state
+                }
+            },
+            Message::Donzo => {
+                UiState {
+                    finished: true,
                     ..ui_state
                 }
             },
@@ -169,17 +559,41 @@ async fn next_message(ui_state: UiState, encoder: &Encoder, rx: &mut UnboundedRe
     }
 }
 
-async fn next_input(ui_state: UiState, event_stream: &mut EventStream) -> UiState {
+///
+/// Stop because of an error of our own, showing the peer why.
+///
+fn stop(transport: &Transport, err: &Error, text: String) -> String {
+    transport.stop(Reason::from_error(err), text.clone());
+    return text;
+}
+
+async fn next_input(ui_state: UiState, transport: &Transport, event_stream: &mut EventStream) -> UiState {
     let mut result = ui_state;
     if let Some(Ok(event)) = event_stream.next().await {
         if event == Event::Key(KeyCode::Esc.into()) {
-            result = UiState {done: true, ..result};
+            if result.finished || result.stopping {
+                result = UiState {done: true, ..result};
+            } else {
+                // let the other side see we've gone before we do
+                transport.stop(Reason::Cancelled, "Cancelled".to_string());
+            }
+        } else if event == Event::Key(KeyCode::Enter.into()) && result.pairing_code.is_some() && !result.paired {
+            transport.pair();
+            result = UiState {
+                message: "Paired".to_string(),
+                paired: true,
+                ..result
+            };
         }
     }
     result
 }
 
-fn update_ui(terminal: &mut Terminal<CrosstermBackend<Stderr>>, terminal_state: UiState) {
+///
+/// Draw everything but a code that goes straight to the terminal, returning where the code goes.
+///
+fn update_ui(terminal: &mut Terminal<CrosstermBackend<Stderr>>, terminal_state: UiState, direct: bool) -> Rect {
+    let mut code_area = Rect::default();
     terminal.draw(|f| {
         let size = f.size();
 
@@ -194,15 +608,27 @@ fn update_ui(terminal: &mut Terminal<CrosstermBackend<Stderr>>, terminal_state:
             .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
             .split(main_chunks[1]);
 
-        let graph = Paragraph::new(Text::from(terminal_state.block_text))
+        let title = match (&terminal_state.pairing_code, terminal_state.paired) {
+            (Some(code), false) => format!("piccp - pairing code {}, press Enter if the other screen shows it too", code),
+            (Some(code), true) => format!("piccp - paired {}", code),
+            (None, _) => "piccp".to_string(),
+        };
+        let block_text = if direct { String::new() } else { terminal_state.block_text };
+        let graph = Paragraph::new(Text::from(block_text))
             .alignment(Alignment::Center)
             .style(Style::default().fg(Color::White).bg(Color::Black))
-            .block(Block::default().title("piccp").borders(Borders::ALL));
+            .block(Block::default().title(title).borders(Borders::ALL));
         f.render_widget(graph, main_chunks[0]);
+        code_area = main_chunks[0];
 
         let segment_num = terminal_state.segment_offset + 1;
+        let title = if terminal_state.file_name.is_empty() {
+            "progress".to_string()
+        } else {
+            terminal_state.file_name.clone()
+        };
         let mut progress = Gauge::default()
-            .block(Block::default().title("progress").borders(Borders::ALL))
+            .block(Block::default().title(title).borders(Borders::ALL))
             .gauge_style(Style::default().fg(Color::Green).bg(Color::Black).add_modifier(Modifier::ITALIC));
         if terminal_state.segment_count >= segment_num {
             progress = progress
@@ -219,21 +645,244 @@ fn update_ui(terminal: &mut Terminal<CrosstermBackend<Stderr>>, terminal_state:
             .block(Block::default().title("log").borders(Borders::ALL));
         f.render_widget(graph, bot_chunks[1]);
     }).unwrap();
+    return code_area;
+}
+
+///
+/// Bitmaps and colour codes are escapes the terminal draws itself, they go straight to it inside
+/// the borders a line at a time.
+///
+fn draw_bitmap(terminal: &mut Terminal<CrosstermBackend<Stderr>>, area: Rect, bitmap: &str) {
+    execute!(terminal.backend_mut(), SavePosition).unwrap();
+    for (i, line) in bitmap.lines().enumerate() {
+        execute!(terminal.backend_mut(), MoveTo(area.x + 1, area.y + 1 + i as u16), Print(line)).unwrap();
+    }
+    execute!(terminal.backend_mut(), RestorePosition).unwrap();
+}
+
+///
+/// The codes of the kind the encoder shows that fit the terminal, one for each lane.
+///
+fn fit(args: &Args, encoder: &Encoder, kind: CodeKind) -> std::result::Result<Tiling, String> {
+    let most = Capacity::largest(encoder.symbology(), args.ec_level);
+    let payload = args.max_payload.map_or(0, |max_payload| max_payload as usize);
+    if payload > most.payload {
+        return Err(format!("No {} code holds a {}b frame, {}b is the most", kind, payload, most.payload));
+    }
+    return Ok(match terminal::size() {
+        Ok((columns, rows)) => match Tiling::fitting(columns, rows, args.lanes, payload, encoder) {
+            Some(tiling) => tiling,
+            None => {
+                let needed = Capacity::holding(encoder.symbology(), payload, args.ec_level).map_or(most.version, |capacity| capacity.version);
+                return Err(match Tiling::fitting(columns, rows, args.lanes, 0, encoder) {
+                    Some(tiling) => format!("A {}b frame needs a version {} {} code but only version {} fits the terminal, try --max-payload {}",
+                                            payload, needed, kind, tiling.capacity.version, tiling.capacity.payload),
+                    None => format!("A {}x{} terminal is too small for any {} code, try a smaller --scale-width and --scale-height", columns, rows, kind),
+                });
+            }
+        },
+        // there's nothing to fit
+        Err(_) => Tiling {
+            capacity: most,
+            across: args.lanes.max(1),
+            lanes: args.lanes.max(1),
+        },
+    });
+}
+
+///
+/// The codes that fit the terminal, one for each lane, and the frame and fragment sizes that
+/// go in them unless they were given.  Frames are sized so they fit whichever of `kinds` is
+/// shown, and the encoder is left showing the last of them.
+///
+fn frame_sizes(args: &Args, encoder: &mut Encoder, kinds: &[CodeKind]) -> std::result::Result<(Tiling, u16, u16), String> {
+    let mut screen: Option<Tiling> = None;
+    for kind in kinds {
+        encoder.switch(*kind)?;
+        let tiling = fit(args, encoder, *kind)?;
+        screen = Some(screen.map_or(tiling, |screen| screen.within(&tiling)));
+    }
+    let screen = screen.ok_or("There's no kind of code to show")?;
+    let max_payload = args.max_payload.map_or(screen.capacity.payload, |max_payload| max_payload as usize);
+    let largest = Capacity { payload: max_payload, ..screen.capacity }.fragment_size();
+    let fragment_size = match args.fragment_size {
+        None => largest,
+        Some(fragment_size) if fragment_size as usize > largest => {
+            return Err(format!("{}b fragments don't fit in {}b frames, try --fragment-size {}", fragment_size, max_payload, largest));
+        }
+        Some(fragment_size) => fragment_size as usize,
+    };
+    if fragment_size < MIN_FRAGMENT_SIZE as usize {
+        return Err(match args.fragment_size {
+            Some(_) => format!("{}b fragments are too small, {}b is the least", fragment_size, MIN_FRAGMENT_SIZE),
+            None => format!("{}b frames leave room for {}b fragments, {}b is the least, try a larger --max-payload", max_payload, fragment_size, MIN_FRAGMENT_SIZE),
+        });
+    }
+    return Ok((screen, max_payload as u16, fragment_size as u16));
+}
+
+///
+/// The keystore commands, run instead of a transfer.
+///
+async fn run_command(command: &Command, args: &Args, keystore: &Keystore) {
+    match command {
+        Command::Keygen { force } => match keystore.generate(*force) {
+            Ok(identity) => println!("Generated {}", to_hex(&identity.public_key())),
+            Err(err) => {
+                eprintln!("Failed to generate a key: {}", err);
+                exit(1);
+            }
+        },
+        Command::ExportKey => match keystore.identity() {
+            Ok(Some(identity)) => {
+                let encoder = Encoder::new(args.scale_width as u32, args.scale_height as u32, !args.hide_quiet_zone, args.ec_level, args.rendering.resolve(), false, CodeKind::Qr)
+                    .expect("QR codes are always built in");
+                println!("{}", encoder.encode(&Frame::new_key(&identity.public_key())));
+                println!("{}", to_hex(&identity.public_key()));
+            }
+            Ok(None) => {
+                eprintln!("There's no identity to export, run keygen first");
+                exit(1);
+            }
+            Err(err) => {
+                eprintln!("Failed to read the keystore: {}", err);
+                exit(1);
+            }
+        },
+        Command::ImportKey { name } => {
+            // nobody's watching the log, the camera just needs somewhere to put it
+            let (log_tx, _log_rx) = unbounded_channel();
+            let (key_tx, mut key_rx) = unbounded_channel();
+            let _camera = Camera::new(move |frame: Frame| {
+                if let ParsedFrame::Key(key) = frame.parsed() {
+                    let _ = key_tx.send(key);
+                }
+            }, Decoder::new(Log::new(log_tx), false, false));
+            eprintln!("Scanning for {}'s key", name);
+            let key = key_rx.recv().await.expect("Camera stopped");
+            match keystore.trust(&key, name) {
+                Ok(_) => println!("Trusted {} as {}", to_hex(&key), name),
+                Err(err) => {
+                    eprintln!("Failed to trust {}: {}", name, err);
+                    exit(1);
+                }
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
+    let keystore = Keystore::open(&args.keystore, args.trust_on_first_use, args.require_signature, args.allow_unsigned);
+    if let Some(command) = &args.command {
+        run_command(command, &args, &keystore).await;
+        return;
+    }
+    let identity = match keystore.identity() {
+        Ok(identity) => identity,
+        Err(err) => {
+            eprintln!("Failed to read the keystore: {}", err);
+            exit(1);
+        }
+    };
+
+    let rendering = args.rendering.resolve();
+    if args.rgb && rendering == Rendering::Quadrant {
+        eprintln!("Quadrants can't be drawn in colour, try --rendering half");
+        exit(1);
+    }
+    let mut encoder = match Encoder::new(args.scale_width as u32, args.scale_height as u32, !args.hide_quiet_zone, args.ec_level, rendering, args.rgb, args.symbology) {
+        Ok(encoder) => encoder,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+    if rendering.is_bitmap() {
+        if let Some(cell) = graphics::cell_size() {
+            encoder.measure(cell);
+        }
+    }
+    // a transfer that waits for the other side starts in QR, and may never leave it
+    let kinds = if args.broadcast || args.fountain { vec![args.symbology] } else { vec![CodeKind::Qr, args.symbology] };
+    let (screen, max_payload, fragment_size) = match frame_sizes(&args, &mut encoder, &kinds) {
+        Ok(sizes) => sizes,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+
     let (tx, mut rx) = unbounded_channel();
     let log = Log::new(tx.clone());
+    if rendering != args.rendering {
+        log.log(format!("Drawing codes with {}", rendering));
+    }
+    log.log(format!("Codes up to version {} fit, frames up to {}b with {}b fragments", screen.capacity.version, max_payload, fragment_size));
+    // each code carries a frame in each of red, green and blue, if the other side reads them
+    let lanes = if args.rgb { 3 * screen.lanes } else { screen.lanes };
+    if lanes > 1 {
+        log.log(format!("Showing up to {} frames at a time in {} codes, {} across", lanes, screen.lanes, screen.across));
+    }
+    let options = TransportOptions {
+        fragment_size,
+        window_size: args.window_size,
+        frame_interval: args.frame_interval,
+        broadcast: args.broadcast,
+        fountain: args.fountain,
+        max_payload,
+        compression: args.compression,
+        passphrase: args.passphrase.clone(),
+        identity,
+        resume_file: if args.is_sender() { None } else { ResumeState::path_for(&args.output_file) },
+        timeout: args.timeout,
+        lanes: screen.lanes,
+        rgb: args.rgb,
+        symbology: args.symbology,
+        pairing: !args.no_pairing,
+    };
+    let data_size = options.data_size(fragment_size);
+    // whoever shows the first code picks the session: the receiver's CTS, or a sender that doesn't wait for one
+    let session = if args.is_sender() == (args.broadcast || args.fountain) {
+        Session::random()
+    } else {
+        Session::new()
+    };
     let transport = if args.input_file.is_empty() {
-        Transport::new(tx.clone(), log.clone(), StdinSourceFactory {}, args.fragment_size).await
+        Transport::new(tx.clone(), log.clone(), StdinSourceFactory {}, session, options).await
+    } else if args.input_file.len() == 1 && !Path::new(&args.input_file[0]).is_dir() {
+        Transport::new(tx.clone(), log.clone(), FileSourceFactory {path: args.input_file[0].clone()}, session, options).await
+    } else {
+        let (manifest, sources) = match Manifest::collect(&args.input_file) {
+            Ok(collected) => collected,
+            Err(err) => {
+                eprintln!("Failed to read {}: {}", args.input_file.join(", "), err);
+                exit(1);
+            }
+        };
+        // a single directory keeps its name, several paths don't have one
+        let name = match args.input_file.as_slice() {
+            [path] => Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+            _ => String::new(),
+        };
+        Transport::new(tx.clone(), log.clone(), TreeSourceFactory {manifest, sources, name}, session, options).await
+    };
+    encoder.lay_out(Some(max_payload as usize).filter(|_| args.fixed_version), screen.across);
+    // until the other side says what it reads, only black and white QR is safe
+    if !args.broadcast && !args.fountain {
+        encoder.switch(CodeKind::Qr).expect("QR codes are always built in");
+        encoder.colour(false);
+    }
+    let mut output = Output::new(&args.output_file, data_size, args.keep_mode);
+    // a broadcasting sender never looks back
+    let _camera = if args.broadcast && args.is_sender() {
+        None
     } else {
-        Transport::new(tx.clone(), log.clone(), FileSourceFactory {path: args.input_file.clone()}, args.fragment_size).await
+        let transport = transport.clone();
+        Some(Camera::new(move |frame| transport.receive_frame(frame), Decoder::new(log, args.rgb, args.v1)))
     };
-    let encoder = Encoder::new(args.scale_width as u32, args.scale_height as u32, !args.hide_quiet_zone);
-    let _camera = Camera::new(transport.clone(), Decoder::new(log));
 
     if !args.is_sender() {
         transport.receive();
@@ -247,23 +896,43 @@ async fn main() {
     let mut event_stream = EventStream::new();
     let mut ui_state = UiState::new();
 
-    update_ui(&mut terminal, ui_state.clone());
+    // how long the reason we stopped stays up, ESC again leaves straight away
+    let mut stop_at: Option<Instant> = None;
+
+    // the bitmap on screen, tui can't tell when it's been drawn over
+    let mut drawn = String::new();
+
+    let direct = encoder.is_direct();
+    update_ui(&mut terminal, ui_state.clone(), direct);
     loop {
         let current_ui_state = ui_state.clone();
         ui_state = select! {
-            res0 = next_message(current_ui_state.clone(), &encoder, &mut rx) => res0,
-            res1 = next_input(current_ui_state, &mut event_stream) => res1,
+            res0 = next_message(current_ui_state.clone(), &mut encoder, &mut output, &transport, args.expect_hash, &keystore, &mut rx) => res0,
+            res1 = next_input(current_ui_state.clone(), &transport, &mut event_stream) => res1,
+            _ = sleep_until(stop_at.unwrap_or_else(Instant::now)), if stop_at.is_some() => UiState {done: true, ..current_ui_state},
         };
 
-        update_ui(&mut terminal, ui_state.clone());
+        let code_area = update_ui(&mut terminal, ui_state.clone(), direct);
+        if direct && ui_state.block_text != drawn {
+            draw_bitmap(&mut terminal, code_area, &ui_state.block_text);
+            drawn = ui_state.block_text.clone();
+        }
 
         if ui_state.done {
             break;
         }
+        if ui_state.stopping && stop_at.is_none() {
+            stop_at = Some(Instant::now() + Duration::from_millis(args.timeout as u64));
+        }
     }
 
     // restore terminal
     disable_raw_mode().unwrap();
     execute!(terminal.backend_mut(), LeaveAlternateScreen, DisableMouseCapture).unwrap();
     terminal.show_cursor().unwrap();
+
+    if ui_state.exit_status != 0 {
+        eprintln!("{}", ui_state.message);
+        exit(ui_state.exit_status);
+    }
 }
@@ -0,0 +1,276 @@
+use std::fs::{File, read_dir, symlink_metadata};
+use std::io::{copy, Result};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use bytes::BufMut;
+
+use crate::hash::{ContentHasher, Hash, HASH_SIZE};
+
+const HAS_MODE: u8 = 0x01;
+const HAS_MODIFIED: u8 = 0x02;
+
+/// present, size, mode, modified, hash, path len
+const ENTRY_HEADER_SIZE: usize = 1 + 8 + 4 + 8 + HASH_SIZE + 2;
+
+///
+/// One file in a manifest.  Paths are relative and always use `/`.
+///
+/// ```text
+/// | present u8 | size u64 | mode u32 | modified u64 | hash [u8; 32] | path len u16 | path ... |
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub mode: Option<u32>,
+    /// Seconds since the epoch
+    pub modified: Option<u64>,
+    pub hash: Hash,
+}
+
+///
+/// The files in a multi-file transfer.  The data of a multi-file transfer is the encoded
+/// manifest followed by the content of every file it lists, in order.
+///
+/// ```text
+/// | length u32 | entry ... |
+/// ```
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    ///
+    /// Walk the given files and directories, hashing everything found.  Directories are
+    /// named after their last component, except the root whose files go in at the top;
+    /// symlinks inside them are skipped so nothing from outside the tree gets sent.  Returns
+    /// the manifest along with where each entry is read from.
+    ///
+    pub fn collect(paths: &[String]) -> Result<(Self, Vec<PathBuf>)> {
+        let mut manifest = Self::default();
+        let mut sources = Vec::new();
+        for path in paths {
+            let path = Path::new(path);
+            // `.` and `..` are named after where they lead, the root has nothing to go by
+            let name = path.file_name()
+                .map(|name| name.to_os_string())
+                .or_else(|| path.canonicalize().ok()?.file_name().map(|name| name.to_os_string()))
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if path.is_dir() {
+                manifest.collect_dir(path, &name, &mut sources)?;
+            } else {
+                manifest.collect_file(path, name, &mut sources)?;
+            }
+        }
+        return Ok((manifest, sources));
+    }
+
+    fn collect_dir(&mut self, dir: &Path, prefix: &str, sources: &mut Vec<PathBuf>) -> Result<()> {
+        let mut children: Vec<_> = read_dir(dir)?.collect::<Result<_>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let name = match prefix {
+                "" => child.file_name().to_string_lossy().to_string(),
+                prefix => format!("{}/{}", prefix, child.file_name().to_string_lossy()),
+            };
+            let file_type = child.file_type()?;
+            if file_type.is_dir() {
+                self.collect_dir(&child.path(), &name, sources)?;
+            } else if file_type.is_file() {
+                self.collect_file(&child.path(), name, sources)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn collect_file(&mut self, path: &Path, name: String, sources: &mut Vec<PathBuf>) -> Result<()> {
+        let metadata = path.metadata()?;
+        let mut hasher = ContentHasher::new();
+        copy(&mut File::open(path)?, &mut hasher)?;
+        #[cfg(unix)]
+        let mode = Some(metadata.permissions().mode());
+        #[cfg(not(unix))]
+        let mode = None;
+        self.entries.push(ManifestEntry {
+            path: name,
+            size: metadata.len(),
+            mode,
+            modified: metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs()),
+            hash: hasher.finish(),
+        });
+        sources.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        return 4 + self.entries.iter().map(|entry| ENTRY_HEADER_SIZE + entry.path.len()).sum::<usize>();
+    }
+
+    ///
+    /// The size of the manifest and all the files it lists.
+    ///
+    pub fn total_size(&self) -> u64 {
+        return self.encoded_size() as u64 + self.entries.iter().map(|entry| entry.size).sum::<u64>();
+    }
+
+    pub fn encode(&self, encoded: &mut Vec<u8>) {
+        encoded.put_u32(self.encoded_size() as u32);
+        for entry in &self.entries {
+            let mut present = 0;
+            present |= if entry.mode.is_some() { HAS_MODE } else { 0 };
+            present |= if entry.modified.is_some() { HAS_MODIFIED } else { 0 };
+            encoded.put_u8(present);
+            encoded.put_u64(entry.size);
+            encoded.put_u32(entry.mode.unwrap_or(0));
+            encoded.put_u64(entry.modified.unwrap_or(0));
+            encoded.put_slice(&entry.hash);
+            encoded.put_u16(entry.path.len() as u16);
+            encoded.put_slice(entry.path.as_bytes());
+        }
+    }
+
+    ///
+    /// How many bytes the manifest at the start of `encoded` takes up, if there's enough of
+    /// it to tell.
+    ///
+    pub fn peek_size(encoded: &[u8]) -> Option<usize> {
+        return Some(u32::from_be_bytes(encoded.get(0..4)?.try_into().unwrap()) as usize);
+    }
+
+    pub fn decode(encoded: &[u8]) -> Option<Self> {
+        let size = Self::peek_size(encoded)?;
+        let mut body = encoded.get(4..size)?;
+        let mut entries = Vec::new();
+        while !body.is_empty() {
+            let header = body.get(0..ENTRY_HEADER_SIZE)?;
+            let present = header[0];
+            let path_len = u16::from_be_bytes(header[ENTRY_HEADER_SIZE - 2..].try_into().unwrap()) as usize;
+            let path = body.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + path_len)?;
+            entries.push(ManifestEntry {
+                path: String::from_utf8(path.to_vec()).ok()?,
+                size: u64::from_be_bytes(header[1..9].try_into().unwrap()),
+                mode: if present & HAS_MODE != 0 { Some(u32::from_be_bytes(header[9..13].try_into().unwrap())) } else { None },
+                modified: if present & HAS_MODIFIED != 0 { Some(u64::from_be_bytes(header[13..21].try_into().unwrap())) } else { None },
+                hash: header[21..21 + HASH_SIZE].try_into().unwrap(),
+            });
+            body = &body[ENTRY_HEADER_SIZE + path_len..];
+        }
+        return Some(Self {
+            entries
+        });
+    }
+}
+
+///
+/// Where a manifest path ends up under `root`, or `None` if it tries to get out: absolute
+/// paths, `..`, anything that isn't a plain name, or an existing symlink along the way.
+///
+pub fn resolve_under(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    for part in path.split('/') {
+        // a backslash or drive letter would be a separator somewhere
+        if part.contains('\\') || part.contains(':') {
+            return None;
+        }
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => resolved.push(name),
+            _ => return None,
+        }
+        if symlink_metadata(&resolved).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return None;
+        }
+    }
+    return if resolved == root { None } else { Some(resolved) };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+
+    ///
+    /// A fresh directory of our own under the system's temporary one.
+    ///
+    fn scratch(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("piccp-manifest-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn plain_paths_resolve_under_the_root() {
+        let root = Path::new("out");
+        assert_eq!(resolve_under(root, "a"), Some(root.join("a")));
+        assert_eq!(resolve_under(root, "a/b/c.txt"), Some(root.join("a").join("b").join("c.txt")));
+    }
+
+    #[test]
+    fn parent_directories_are_refused() {
+        let root = Path::new("out");
+        assert_eq!(resolve_under(root, ".."), None);
+        assert_eq!(resolve_under(root, "../a"), None);
+        assert_eq!(resolve_under(root, "a/../../b"), None);
+        assert_eq!(resolve_under(root, "a/./b"), None);
+    }
+
+    #[test]
+    fn absolute_paths_are_refused() {
+        let root = Path::new("out");
+        assert_eq!(resolve_under(root, "/etc/passwd"), None);
+        assert_eq!(resolve_under(root, "C:"), None);
+        assert_eq!(resolve_under(root, "C:/Windows"), None);
+        assert_eq!(resolve_under(root, "a\\..\\..\\b"), None);
+        assert_eq!(resolve_under(root, "\\b"), None);
+    }
+
+    #[test]
+    fn empty_paths_are_refused() {
+        let root = Path::new("out");
+        assert_eq!(resolve_under(root, ""), None);
+        assert_eq!(resolve_under(root, "a//b"), None);
+        assert_eq!(resolve_under(root, "a/"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_refused() {
+        let root = scratch("symlink");
+        let outside = scratch("outside");
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        assert_eq!(resolve_under(&root, "link"), None);
+        assert_eq!(resolve_under(&root, "link/a"), None);
+        assert_eq!(resolve_under(&root, "a"), Some(root.join("a")));
+        remove_dir_all(&root).unwrap();
+        remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn dot_dot_is_named_after_the_directory() {
+        let root = scratch("dot-dot");
+        write(root.join("a"), b"a").unwrap();
+        create_dir_all(root.join("b")).unwrap();
+        write(root.join("b").join("c"), b"c").unwrap();
+        let name = root.file_name().unwrap().to_string_lossy().to_string();
+        let (manifest, sources) = Manifest::collect(&[root.join("b").join("..").to_string_lossy().to_string()]).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, [format!("{}/a", name), format!("{}/b/c", name)]);
+        assert_eq!(sources.len(), 2);
+        for entry in &manifest.entries {
+            assert!(resolve_under(Path::new("out"), &entry.path).is_some(), "{}", entry.path);
+        }
+        remove_dir_all(&root).unwrap();
+    }
+}
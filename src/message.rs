@@ -1,13 +1,42 @@
 use crate::Frame;
+use crate::handshake::Capabilities;
+use crate::hash::Hash;
+use crate::identity::Signature;
+use crate::metadata::FileMetadata;
+use crate::pairing::PublicKey;
+use crate::reason::Reason;
+use crate::resume::ResumeState;
+use crate::symbology::CodeKind;
 
 #[derive(Debug, Clone)]
 pub enum Message {
-    SendFrame(usize),
+    Hello(Capabilities, Option<PublicKey>),
+    /// Where the receiver is, and the hash of the file it's resuming if it is
+    SendWindow(usize, Vec<u8>, Option<Hash>),
     ReceiveNextFrame,
     ReceiveFrame(Frame),
+    Verified(Hash),
+    Paired,
+    Tick,
 
     WriteData(Frame),
+    /// Several frames shown at once, one code each
+    WriteLanes(Vec<Frame>),
+    /// Show this kind of code from now on
+    Symbology(CodeKind),
+    /// Lay frames over each other in red, green and blue from now on, or stop
+    Colour(bool),
+    Metadata(FileMetadata),
     AppendToOutput(Frame),
+    WriteToOutput(Frame, usize),
+    Progress(usize, usize),
+    Resume(ResumeState),
+    Verify(Option<Hash>, Option<Signature>),
+    Pairing(String),
+    /// We've stopped and shown the peer why
+    Failed(Reason, String),
+    /// The peer has stopped
+    Stopped(Reason, String),
     Log(String),
     Donzo
 }
\ No newline at end of file
@@ -0,0 +1,284 @@
+use std::fs::Metadata;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use bytes::BufMut;
+
+use crate::handshake::Compression;
+use crate::hash::{Hash, HASH_SIZE};
+use crate::identity::{Signature, SIGNATURE_SIZE};
+
+const HAS_SIZE: u8 = 0x01;
+const HAS_MODE: u8 = 0x02;
+const HAS_MODIFIED: u8 = 0x04;
+const HAS_HASH: u8 = 0x08;
+const IS_MANIFEST: u8 = 0x10;
+const DEFLATED: u8 = 0x20;
+const ZSTD_COMPRESSED: u8 = 0x40;
+const ENCRYPTED: u8 = 0x80;
+
+/// Media types for the extensions most likely to be sent, sorted by extension
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("7z", "application/x-7z-compressed"),
+    ("bz2", "application/x-bzip2"),
+    ("csv", "text/csv"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("json", "application/json"),
+    ("md", "text/markdown"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("txt", "text/plain"),
+    ("wav", "audio/wav"),
+    ("webp", "image/webp"),
+    ("xml", "application/xml"),
+    ("xz", "application/x-xz"),
+    ("zip", "application/zip"),
+    ("zst", "application/zstd"),
+];
+
+///
+/// What the receiver gets told about the file before any of its data.
+///
+/// ```text
+/// | present u8 | size u64 | mode u32 | modified u64 | segment size u32 | name len u16 | name ... | mime len u8 | mime ... | hash [u8; 32]? | signature ...? |
+/// ```
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub name: String,
+    pub size: Option<u64>,
+    pub mode: Option<u32>,
+    /// Seconds since the epoch
+    pub modified: Option<u64>,
+    /// How much data the sender puts in each segment, 0 when it doesn't say.  Receivers that
+    /// never agreed a fragment size with the sender place segments by this.
+    pub segment_size: Option<u32>,
+    pub mime_type: Option<String>,
+    /// Only senders that read everything before showing the metadata know this
+    pub hash: Option<Hash>,
+    /// The sender's signature over the hash.  There's no flag for it, it's there if there's
+    /// room for it after the hash.
+    pub signature: Option<Signature>,
+    /// The data is a manifest followed by the files it lists rather than a single file
+    pub manifest: bool,
+    /// How the data was compressed.  The size and hash are of the original data.
+    pub compression: Compression,
+    /// The segments are sealed with a key from a shared passphrase
+    pub encrypted: bool,
+}
+
+impl FileMetadata {
+    pub fn from_path(path: &str, metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = Some(metadata.permissions().mode());
+        #[cfg(not(unix))]
+        let mode = None;
+        return Self {
+            name: Path::new(path).file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            size: Some(metadata.len()),
+            mode,
+            modified: metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs()),
+            segment_size: None,
+            mime_type: mime_type_of(Path::new(path)),
+            hash: None,
+            signature: None,
+            manifest: false,
+            compression: Compression::None,
+            encrypted: false,
+        }
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        return 1 + 8 + 4 + 8 + 4 + 2 + self.name().len() + 1 + self.mime_type().len()
+            + self.hash.map_or(0, |_| HASH_SIZE + self.signature.map_or(0, |_| SIGNATURE_SIZE));
+    }
+
+    ///
+    /// The name as it's sent, cut short on a character boundary if its length won't fit.
+    ///
+    fn name(&self) -> &str {
+        return clamp(&self.name, u16::MAX as usize);
+    }
+
+    fn mime_type(&self) -> &str {
+        return clamp(self.mime_type.as_deref().unwrap_or(""), u8::MAX as usize);
+    }
+
+    pub fn encode(&self, encoded: &mut Vec<u8>) {
+        let mut present = 0;
+        present |= if self.size.is_some() { HAS_SIZE } else { 0 };
+        present |= if self.mode.is_some() { HAS_MODE } else { 0 };
+        present |= if self.modified.is_some() { HAS_MODIFIED } else { 0 };
+        present |= if self.hash.is_some() { HAS_HASH } else { 0 };
+        present |= if self.manifest { IS_MANIFEST } else { 0 };
+        present |= match self.compression {
+            Compression::None => 0,
+            Compression::Deflate => DEFLATED,
+            Compression::Zstd => ZSTD_COMPRESSED,
+        };
+        present |= if self.encrypted { ENCRYPTED } else { 0 };
+        encoded.put_u8(present);
+        encoded.put_u64(self.size.unwrap_or(0));
+        encoded.put_u32(self.mode.unwrap_or(0));
+        encoded.put_u64(self.modified.unwrap_or(0));
+        encoded.put_u32(self.segment_size.unwrap_or(0));
+        let name = self.name();
+        encoded.put_u16(name.len() as u16);
+        encoded.put_slice(name.as_bytes());
+        let mime_type = self.mime_type();
+        encoded.put_u8(mime_type.len() as u8);
+        encoded.put_slice(mime_type.as_bytes());
+        if let Some(hash) = &self.hash {
+            encoded.put_slice(hash);
+            if let Some(signature) = &self.signature {
+                signature.encode(encoded);
+            }
+        }
+    }
+
+    pub fn decode(encoded: &[u8]) -> Option<Self> {
+        if encoded.len() < 1 + 8 + 4 + 8 + 4 + 2 {
+            return None;
+        }
+        let present = encoded[0];
+        let size = u64::from_be_bytes(encoded[1..9].try_into().unwrap());
+        let mode = u32::from_be_bytes(encoded[9..13].try_into().unwrap());
+        let modified = u64::from_be_bytes(encoded[13..21].try_into().unwrap());
+        let segment_size = u32::from_be_bytes(encoded[21..25].try_into().unwrap());
+        let name_len = u16::from_be_bytes(encoded[25..27].try_into().unwrap()) as usize;
+        let name = encoded.get(27..27 + name_len)?;
+        let mime_len = *encoded.get(27 + name_len)? as usize;
+        let mime_end = 28 + name_len + mime_len;
+        let mime_type = encoded.get(28 + name_len..mime_end)?;
+        let (hash, signature) = if present & HAS_HASH != 0 {
+            let hash = encoded.get(mime_end..mime_end + HASH_SIZE)?.try_into().unwrap();
+            (Some(hash), Signature::decode(&encoded[mime_end + HASH_SIZE..]))
+        } else {
+            (None, None)
+        };
+        return Some(Self {
+            name: String::from_utf8(name.to_vec()).ok()?,
+            size: if present & HAS_SIZE != 0 { Some(size) } else { None },
+            mode: if present & HAS_MODE != 0 { Some(mode) } else { None },
+            modified: if present & HAS_MODIFIED != 0 { Some(modified) } else { None },
+            segment_size: if segment_size > 0 { Some(segment_size) } else { None },
+            mime_type: if mime_len > 0 { Some(String::from_utf8(mime_type.to_vec()).ok()?) } else { None },
+            hash,
+            signature,
+            manifest: present & IS_MANIFEST != 0,
+            compression: match (present & DEFLATED != 0, present & ZSTD_COMPRESSED != 0) {
+                (false, false) => Compression::None,
+                (true, false) => Compression::Deflate,
+                (false, true) => Compression::Zstd,
+                (true, true) => return None,
+            },
+            encrypted: present & ENCRYPTED != 0,
+        });
+    }
+}
+
+///
+/// The media type going by the extension, if it's one we know.
+///
+fn mime_type_of(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    return MIME_TYPES.binary_search_by_key(&extension.as_str(), |(known, _)| *known).ok()
+        .map(|i| MIME_TYPES[i].1.to_string());
+}
+
+///
+/// At most `max` bytes of `text`, without splitting a character.
+///
+fn clamp(text: &str, max: usize) -> &str {
+    let mut len = text.len().min(max);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    return &text[0..len];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+
+    fn metadata() -> FileMetadata {
+        return FileMetadata {
+            name: "notes.txt".to_string(),
+            size: Some(1234),
+            mode: Some(0o644),
+            modified: Some(1_700_000_000),
+            segment_size: Some(500),
+            mime_type: Some("text/plain".to_string()),
+            hash: Some([9; 32]),
+            signature: Some(Identity::from_bytes(&[5; 32]).sign(&[9; 32])),
+            manifest: false,
+            compression: Compression::Zstd,
+            encrypted: true,
+        };
+    }
+
+    fn round_trip(metadata: &FileMetadata) -> Option<FileMetadata> {
+        let mut encoded = Vec::new();
+        metadata.encode(&mut encoded);
+        assert_eq!(encoded.len(), metadata.encoded_size());
+        return FileMetadata::decode(&encoded);
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        assert_eq!(round_trip(&metadata()), Some(metadata()));
+        assert_eq!(round_trip(&FileMetadata::default()), Some(FileMetadata::default()));
+        let unsigned = FileMetadata { signature: None, compression: Compression::Deflate, manifest: true, ..metadata() };
+        assert_eq!(round_trip(&unsigned), Some(unsigned));
+        // there's nowhere for a signature without a hash
+        let hashless = FileMetadata { hash: None, ..metadata() };
+        assert_eq!(round_trip(&hashless), Some(FileMetadata { signature: None, ..hashless }));
+    }
+
+    #[test]
+    fn truncated_metadata_is_refused() {
+        let mut encoded = Vec::new();
+        metadata().encode(&mut encoded);
+        let hash_end = metadata().encoded_size() - SIGNATURE_SIZE;
+        for len in (0..hash_end).step_by(3) {
+            assert_eq!(FileMetadata::decode(&encoded[0..len]), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn mime_types_come_from_the_extension() {
+        assert!(MIME_TYPES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let metadata = std::fs::metadata(".").unwrap();
+        assert_eq!(FileMetadata::from_path("dir/photo.JPG", &metadata).mime_type, Some("image/jpeg".to_string()));
+        assert_eq!(FileMetadata::from_path("notes.txt", &metadata).mime_type, Some("text/plain".to_string()));
+        assert_eq!(FileMetadata::from_path("README", &metadata).mime_type, None);
+        assert_eq!(FileMetadata::from_path("archive.unknown", &metadata).mime_type, None);
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_character_boundary() {
+        assert_eq!(clamp("héllo", 2), "h");
+        assert_eq!(clamp("héllo", 3), "hé");
+        assert_eq!(clamp("héllo", 100), "héllo");
+        let long = FileMetadata { name: "é".repeat(40000), mime_type: Some("x".repeat(300)), ..metadata() };
+        let decoded = round_trip(&long).unwrap();
+        assert_eq!(decoded.name, "é".repeat(32767));
+        assert_eq!(decoded.mime_type, Some("x".repeat(255)));
+    }
+}
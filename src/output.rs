@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File, OpenOptions, read};
+#[cfg(unix)]
+use std::fs::Permissions;
+use std::io::{copy, Error, ErrorKind, Result, Seek, SeekFrom, stdout, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::compression::Decompressor;
+use crate::hash::{ContentHasher, Hash};
+use crate::manifest::{Manifest, resolve_under};
+use crate::metadata::FileMetadata;
+use crate::resume::ResumeState;
+
+///
+/// Where received data ends up.  Segments may arrive in any order; a file is written in
+/// place while stdout and compressed data have to wait for the gaps to be filled.
+///
+pub struct Output {
+    path: String,
+    file: Option<File>,
+    metadata: Option<FileMetadata>,
+    fragment_size: usize,
+    next_offset: usize,
+    pending: BTreeMap<usize, Vec<u8>>,
+    /// What's gone to stdout, which can't be read back
+    hasher: ContentHasher,
+    tree: Option<Tree>,
+    appended: u64,
+    decompressor: Option<Decompressor>,
+    /// What's been written, kept up to date on disk in case we're interrupted
+    progress: Option<(ResumeState, PathBuf)>,
+    /// Give files the permissions the sender had
+    keep_mode: bool,
+}
+
+impl Output {
+    pub fn new(path: &str, fragment_size: usize, keep_mode: bool) -> Self {
+        return Self {
+            path: path.to_string(),
+            file: None,
+            metadata: None,
+            fragment_size,
+            next_offset: 0,
+            pending: BTreeMap::new(),
+            hasher: ContentHasher::new(),
+            tree: None,
+            appended: 0,
+            decompressor: None,
+            progress: None,
+            keep_mode,
+        };
+    }
+
+    ///
+    /// Keep track of what's written in `state`, picking up from wherever it says we got to.
+    /// Must be called before `prepare`.
+    ///
+    pub fn resume(&mut self, state: ResumeState) {
+        if let Some(path) = ResumeState::path_for(&self.path) {
+            self.next_offset = state.base();
+            self.appended = (state.base() * state.segment_size) as u64;
+            self.progress = Some((state, path));
+        }
+    }
+
+    fn record(&mut self, offset: usize) -> Result<()> {
+        if let Some((state, path)) = &mut self.progress {
+            state.mark(offset);
+            state.save(path)?;
+        }
+        return Ok(());
+    }
+
+    ///
+    /// Get ready for the file the sender described.  If we were pointed at a directory the
+    /// file is created in there under the sender's name.  Several files are only unpacked
+    /// under a directory we were pointed at, never wherever we happen to be.
+    ///
+    pub fn prepare(&mut self, metadata: &FileMetadata) -> Result<()> {
+        if let Some(segment_size) = metadata.segment_size {
+            self.fragment_size = segment_size as usize;
+        }
+        self.metadata = Some(metadata.clone());
+        self.decompressor = Decompressor::new(metadata.compression, metadata.size)?;
+        if metadata.manifest {
+            if self.path.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "The sender has several files, give -o a directory to put them in"));
+            }
+            let root = PathBuf::from(&self.path);
+            create_dir_all(&root)?;
+            self.tree = Some(Tree::new(root, self.keep_mode));
+            return Ok(());
+        }
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        let resumed = self.appended;
+        let file = self.open()?;
+        if let Some(size) = metadata.size {
+            file.set_len(size)?;
+        }
+        file.seek(SeekFrom::Start(resumed))?;
+        return Ok(());
+    }
+
+    fn resolve(&self) -> Result<PathBuf> {
+        let path = Path::new(&self.path);
+        if !path.is_dir() {
+            return Ok(path.to_path_buf());
+        }
+        let name = self.metadata.as_ref()
+            .and_then(|metadata| Path::new(&metadata.name).file_name())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "The sender didn't name the file"))?;
+        return Ok(path.join(name));
+    }
+
+    fn open(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let path = self.resolve()?;
+            // what was written before we were interrupted is kept
+            let resuming = self.progress.as_ref().is_some_and(|(state, _)| state.written().next().is_some());
+            self.file = Some(OpenOptions::new().write(true).create(true).truncate(!resuming).open(path)?);
+        }
+        return Ok(self.file.as_mut().unwrap());
+    }
+
+    pub fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.next_offset += 1;
+        match &mut self.decompressor {
+            Some(decompressor) => {
+                let data = decompressor.decompress(data)?;
+                self.append_plain(&data)?;
+            }
+            None => self.append_plain(data)?,
+        }
+        return self.record(self.next_offset - 1);
+    }
+
+    fn append_plain(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(tree) = &mut self.tree {
+            tree.write(self.appended, data)?;
+        } else if self.path.is_empty() {
+            stdout().lock().write_all(data)?;
+            self.hasher.update(data);
+        } else {
+            self.open()?.write_all(data)?;
+        }
+        self.appended += data.len() as u64;
+        return Ok(());
+    }
+
+    ///
+    /// Check a segment is as long as the sender said they'd be, or what's left of the file
+    /// for the last of them, before it's written where that says it goes.
+    ///
+    fn check_length(&self, offset: usize, data: &[u8]) -> Result<()> {
+        let size = self.metadata.as_ref()
+            .filter(|_| self.decompressor.is_none())
+            .and_then(|metadata| metadata.size);
+        let fits = match size {
+            Some(size) => data.len() as u64 == size.saturating_sub((offset * self.fragment_size) as u64).min(self.fragment_size as u64),
+            None => data.len() <= self.fragment_size,
+        };
+        if !fits {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{}b doesn't fit {}b segments", data.len(), self.fragment_size)));
+        }
+        return Ok(());
+    }
+
+    pub fn write_segment(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.check_length(offset, data)?;
+        if self.decompressor.is_none() {
+            if let Some(tree) = &mut self.tree {
+                return tree.write((offset * self.fragment_size) as u64, data);
+            }
+            if !self.path.is_empty() {
+                let position = (offset * self.fragment_size) as u64;
+                let file = self.open()?;
+                file.seek(SeekFrom::Start(position))?;
+                file.write_all(data)?;
+                return self.record(offset);
+            }
+        }
+        if offset < self.next_offset {
+            return Ok(());
+        }
+        self.pending.insert(offset, data.to_vec());
+        while let Some(data) = self.pending.remove(&self.next_offset) {
+            self.append(&data)?;
+        }
+        return Ok(());
+    }
+
+    ///
+    /// Put the finishing touches on a complete file, its mode and modification time, and
+    /// return the hash of what was written.  Files are read back so the hash covers what
+    /// actually made it to disk.
+    ///
+    pub fn finish(&mut self) -> Result<Hash> {
+        if let Some((_, path)) = self.progress.take() {
+            ResumeState::remove(&path);
+        }
+        if let Some(decompressor) = self.decompressor.take() {
+            let data = decompressor.finish()?;
+            self.append_plain(&data)?;
+        }
+        if let Some(tree) = &mut self.tree {
+            return tree.finish();
+        }
+        if self.path.is_empty() {
+            stdout().lock().flush()?;
+            return Ok(self.hasher.finish());
+        }
+        let metadata = self.metadata.clone().unwrap_or_default();
+        self.open()?.flush()?;
+        copy(&mut File::open(self.resolve()?)?, &mut self.hasher)?;
+        let hash = self.hasher.finish();
+        let mode = metadata.mode.filter(|_| self.keep_mode);
+        restore(self.open()?, mode, metadata.modified)?;
+        return Ok(hash);
+    }
+
+    ///
+    /// The files of a multi-file transfer that didn't match their hash in the manifest.
+    ///
+    pub fn damaged(&self) -> Vec<String> {
+        return self.tree.as_ref().map_or(Vec::new(), |tree| tree.damaged.clone());
+    }
+}
+
+///
+/// Give a file the sender's modification time, and permissions if we were given them.  Only
+/// the read, write and execute bits are taken, a peer doesn't get to make anything setuid.
+///
+fn restore(file: &File, mode: Option<u32>, modified: Option<u64>) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        file.set_permissions(Permissions::from_mode(mode & 0o777))?;
+    }
+    if let Some(modified) = modified {
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+    }
+    return Ok(());
+}
+
+///
+/// A multi-file transfer being unpacked under a directory.  Nothing can be written until
+/// the manifest at the start of the data has arrived.
+///
+struct Tree {
+    root: PathBuf,
+    manifest: Option<Manifest>,
+    /// Where each file is written and where its data starts
+    files: Vec<(PathBuf, u64)>,
+    early: BTreeMap<u64, Vec<u8>>,
+    damaged: Vec<String>,
+    keep_mode: bool,
+}
+
+impl Tree {
+    fn new(root: PathBuf, keep_mode: bool) -> Self {
+        return Self {
+            root,
+            manifest: None,
+            files: Vec::new(),
+            early: BTreeMap::new(),
+            damaged: Vec::new(),
+            keep_mode,
+        };
+    }
+
+    fn write(&mut self, position: u64, data: &[u8]) -> Result<()> {
+        if self.manifest.is_some() {
+            return self.write_files(position, data);
+        }
+        self.early.insert(position, data.to_vec());
+        let mut head = Vec::new();
+        for (position, data) in &self.early {
+            let position = *position as usize;
+            if position > head.len() {
+                break;
+            }
+            if position + data.len() > head.len() {
+                head.extend_from_slice(&data[head.len() - position..]);
+            }
+        }
+        match Manifest::peek_size(&head) {
+            Some(size) if head.len() >= size => {}
+            _ => return Ok(()),
+        }
+        let manifest = Manifest::decode(&head)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The manifest is corrupt"))?;
+        self.unpack(manifest)?;
+        for (position, data) in std::mem::take(&mut self.early) {
+            self.write_files(position, &data)?;
+        }
+        return Ok(());
+    }
+
+    ///
+    /// Create every file in the manifest, refusing any that would land outside the root.
+    ///
+    fn unpack(&mut self, manifest: Manifest) -> Result<()> {
+        let mut start = manifest.encoded_size() as u64;
+        for entry in &manifest.entries {
+            let path = resolve_under(&self.root, &entry.path)
+                .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, format!("Refusing to write {}", entry.path)))?;
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            OpenOptions::new().write(true).create(true).truncate(true).open(&path)?.set_len(entry.size)?;
+            self.files.push((path, start));
+            start += entry.size;
+        }
+        self.manifest = Some(manifest);
+        return Ok(());
+    }
+
+    fn write_files(&mut self, position: u64, data: &[u8]) -> Result<()> {
+        let end = position + data.len() as u64;
+        let manifest = self.manifest.as_ref().unwrap();
+        for ((path, start), entry) in self.files.iter().zip(&manifest.entries) {
+            let from = position.max(*start);
+            let to = end.min(start + entry.size);
+            if from >= to {
+                continue;
+            }
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            file.seek(SeekFrom::Start(from - start))?;
+            file.write_all(&data[(from - position) as usize..(to - position) as usize])?;
+        }
+        return Ok(());
+    }
+
+    ///
+    /// Read every file back, checking it against the manifest, and hash the lot the same
+    /// way the sender did.
+    ///
+    fn finish(&mut self) -> Result<Hash> {
+        let manifest = self.manifest.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "The manifest never arrived"))?;
+        let mut encoded = Vec::with_capacity(manifest.encoded_size());
+        manifest.encode(&mut encoded);
+        let mut hasher = ContentHasher::new();
+        hasher.update(&encoded);
+        self.damaged.clear();
+        for ((path, _), entry) in self.files.iter().zip(&manifest.entries) {
+            let data = read(path)?;
+            hasher.update(&data);
+            let mut file_hasher = ContentHasher::new();
+            file_hasher.update(&data);
+            if file_hasher.finish() != entry.hash {
+                self.damaged.push(entry.path.clone());
+            }
+            restore(&File::open(path)?, entry.mode.filter(|_| self.keep_mode), entry.modified)?;
+        }
+        return Ok(hasher.finish());
+    }
+}
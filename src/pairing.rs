@@ -0,0 +1,63 @@
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey as DalekPublicKey};
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+pub type PublicKey = [u8; PUBLIC_KEY_SIZE];
+
+///
+/// An ephemeral X25519 key exchange both sides turn into a short code.  The code covers
+/// both public keys so anything in the middle swapping them out gets a different code on
+/// each screen.  The operators compare the codes and press a key before any data moves.
+///
+/// The shared secret goes no further than the code.  Pairing only shows who was on the
+/// other end when it started, nothing later is authenticated by it, so something that can
+/// show codes to the receiver once the operators have paired can still slip frames in.
+/// A `--passphrase` seals the data and everything said about it, and a signature from a
+/// trusted key shows who sent the whole file.
+///
+pub struct Pairing {
+    secret: Option<EphemeralSecret>,
+    public_key: PublicKey,
+    code: Option<String>,
+}
+
+impl Pairing {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let public_key = DalekPublicKey::from(&secret).to_bytes();
+        return Self {
+            secret: Some(secret),
+            public_key,
+            code: None,
+        };
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        return &self.public_key;
+    }
+
+    ///
+    /// The code for the peer's key.  Our secret can only be used once so a peer that shows
+    /// up again with another key keeps the first code, which then won't match theirs.
+    ///
+    pub fn pair(&mut self, peer: &PublicKey) -> String {
+        if let Some(secret) = self.secret.take() {
+            let shared = secret.diffie_hellman(&DalekPublicKey::from(*peer));
+            let (first, second) = if self.public_key <= *peer {
+                (&self.public_key, peer)
+            } else {
+                (peer, &self.public_key)
+            };
+            let mut hasher = Sha256::new();
+            hasher.update(b"piccp pairing");
+            hasher.update(shared.as_bytes());
+            hasher.update(first);
+            hasher.update(second);
+            let digest = hasher.finalize();
+            let code = u32::from_be_bytes(digest[0..4].try_into().unwrap()) % 1_000_000;
+            self.code = Some(format!("{:03} {:03}", code / 1000, code % 1000));
+        }
+        return self.code.clone().unwrap();
+    }
+}
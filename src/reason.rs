@@ -0,0 +1,95 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{Error, ErrorKind};
+
+///
+/// Why a transfer was stopped, carried in ABORT and ERROR frames so the other side can say
+/// so too.  Each has its own exit status.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Cancelled,
+    Io,
+    HashMismatch,
+    DiskFull,
+    /// One side has a passphrase and the other isn't using it
+    Encryption,
+    /// The two sides can't agree on how to go about the transfer
+    Incompatible,
+    /// What we wrote wasn't signed by the key that says it was
+    BadSignature,
+    /// Signed by a key we don't trust
+    Untrusted,
+    /// From a newer peer
+    Unknown(u8),
+}
+
+impl Reason {
+    pub fn from_code(code: u8) -> Self {
+        return match code {
+            1 => Reason::Cancelled,
+            2 => Reason::Io,
+            3 => Reason::HashMismatch,
+            4 => Reason::DiskFull,
+            5 => Reason::Encryption,
+            6 => Reason::Incompatible,
+            7 => Reason::BadSignature,
+            8 => Reason::Untrusted,
+            code => Reason::Unknown(code),
+        };
+    }
+
+    pub fn code(&self) -> u8 {
+        return match self {
+            Reason::Cancelled => 1,
+            Reason::Io => 2,
+            Reason::HashMismatch => 3,
+            Reason::DiskFull => 4,
+            Reason::Encryption => 5,
+            Reason::Incompatible => 6,
+            Reason::BadSignature => 7,
+            Reason::Untrusted => 8,
+            Reason::Unknown(code) => *code,
+        };
+    }
+
+    pub fn from_error(err: &Error) -> Self {
+        return match err.kind() {
+            ErrorKind::StorageFull => Reason::DiskFull,
+            _ => Reason::Io,
+        };
+    }
+
+    ///
+    /// 1 is left for failing to start at all.  Reasons added since the first keep clear of
+    /// the ones already in use.
+    ///
+    pub fn exit_status(&self) -> i32 {
+        return match self {
+            Reason::Cancelled => 2,
+            Reason::Io => 3,
+            Reason::HashMismatch => 4,
+            Reason::DiskFull => 5,
+            Reason::Unknown(_) => 6,
+            Reason::Encryption => 7,
+            Reason::Incompatible => 8,
+            Reason::BadSignature => 9,
+            Reason::Untrusted => 10,
+        };
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        return match self {
+            Reason::Cancelled => f.write_str("cancelled"),
+            Reason::Io => f.write_str("I/O error"),
+            Reason::HashMismatch => f.write_str("hash mismatch"),
+            Reason::DiskFull => f.write_str("disk full"),
+            Reason::Encryption => f.write_str("encryption mismatch"),
+            Reason::Incompatible => f.write_str("incompatible settings"),
+            Reason::BadSignature => f.write_str("bad signature"),
+            Reason::Untrusted => f.write_str("untrusted key"),
+            Reason::Unknown(code) => write!(f, "reason {}", code),
+        };
+    }
+}
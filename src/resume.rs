@@ -0,0 +1,202 @@
+use std::collections::BTreeSet;
+use std::fs::{read, remove_file, rename, write};
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use bytes::BufMut;
+
+use crate::handshake::Compression;
+use crate::hash::{Hash, HASH_SIZE};
+use crate::metadata::FileMetadata;
+
+/// Kept next to a file being received, or in the directory it's received into
+const STATE_SUFFIX: &str = ".piccp-resume";
+
+///
+/// What a receiver has written so far, kept on disk so an interrupted transfer can pick up
+/// where it left off.  It's tied to the content hash the sender announced and the size of
+/// the segments, so only the same file cut the same way is resumed.  Compressed data and
+/// several files aren't written in a way that can be picked up again.
+///
+/// ```text
+/// | hash [u8; 32] | segment size u32 | base u64 | received ... |
+/// ```
+///
+/// Everything before `base` has been written and bit `i` of `received` is segment
+/// `base + i`, just like a CTS.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeState {
+    pub hash: Hash,
+    pub segment_size: usize,
+    base: usize,
+    received: BTreeSet<usize>,
+}
+
+impl ResumeState {
+    pub fn new(hash: Hash, segment_size: usize) -> Self {
+        return Self {
+            hash,
+            segment_size,
+            base: 0,
+            received: BTreeSet::new(),
+        };
+    }
+
+    ///
+    /// Where the state for `output` is kept.  Nothing is kept for stdout.
+    ///
+    pub fn path_for(output: &str) -> Option<PathBuf> {
+        if output.is_empty() {
+            return None;
+        }
+        let path = Path::new(output);
+        return Some(if path.is_dir() {
+            path.join(STATE_SUFFIX)
+        } else {
+            PathBuf::from(format!("{}{}", output, STATE_SUFFIX))
+        });
+    }
+
+    ///
+    /// Can a transfer described by this metadata be picked up again later?
+    ///
+    pub fn is_resumable(metadata: &FileMetadata) -> bool {
+        return metadata.hash.is_some() && metadata.compression == Compression::None && !metadata.manifest;
+    }
+
+    ///
+    /// Is this state for the transfer the sender just described?
+    ///
+    pub fn matches(&self, metadata: &FileMetadata, segment_size: usize) -> bool {
+        return Self::is_resumable(metadata) && metadata.hash == Some(self.hash) && segment_size == self.segment_size;
+    }
+
+    ///
+    /// The first segment that hasn't been written.
+    ///
+    pub fn base(&self) -> usize {
+        return self.base;
+    }
+
+    ///
+    /// Every segment that has been written.
+    ///
+    pub fn written(&self) -> impl Iterator<Item = usize> + '_ {
+        return (0..self.base).chain(self.received.iter().copied());
+    }
+
+    pub fn mark(&mut self, offset: usize) {
+        if offset < self.base {
+            return;
+        }
+        self.received.insert(offset);
+        while self.received.remove(&self.base) {
+            self.base += 1;
+        }
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        let encoded = read(path).ok()?;
+        let header = encoded.get(0..HASH_SIZE + 4 + 8)?;
+        let base = u64::from_be_bytes(header[HASH_SIZE + 4..].try_into().unwrap()) as usize;
+        let received = &encoded[header.len()..];
+        return Some(Self {
+            hash: header[0..HASH_SIZE].try_into().unwrap(),
+            segment_size: u32::from_be_bytes(header[HASH_SIZE..HASH_SIZE + 4].try_into().unwrap()) as usize,
+            base,
+            received: (0..received.len() * 8)
+                .filter(|i| received[i / 8] & (1 << (i % 8)) != 0)
+                .map(|i| base + i)
+                .collect(),
+        });
+    }
+
+    ///
+    /// Write the state out, replacing the last one in one go so a crash can't leave half
+    /// of it behind.
+    ///
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let span = self.received.iter().next_back().map_or(0, |last| last - self.base + 1);
+        let mut encoded = Vec::with_capacity(HASH_SIZE + 4 + 8 + (span + 7) / 8);
+        encoded.put_slice(&self.hash);
+        encoded.put_u32(self.segment_size as u32);
+        encoded.put_u64(self.base as u64);
+        let mut received = vec![0u8; (span + 7) / 8];
+        for offset in &self.received {
+            let i = offset - self.base;
+            received[i / 8] |= 1 << (i % 8);
+        }
+        encoded.put_slice(&received);
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        write(&partial, encoded)?;
+        return rename(partial, path);
+    }
+
+    pub fn remove(path: &Path) {
+        let _ = remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    ///
+    /// Somewhere of our own under the system's temporary directory to keep a state.
+    ///
+    fn state_path(name: &str) -> PathBuf {
+        return temp_dir().join(format!("piccp-resume-{}-{}", name, std::process::id()));
+    }
+
+    #[test]
+    fn marking_moves_the_base_past_everything_in_order() {
+        let mut state = ResumeState::new([1; 32], 100);
+        state.mark(1);
+        state.mark(3);
+        assert_eq!(state.base(), 0);
+        state.mark(0);
+        assert_eq!(state.base(), 2);
+        state.mark(1);
+        assert_eq!(state.written().collect::<Vec<_>>(), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn saved_state_loads_back() {
+        let path = state_path("round-trip");
+        let mut state = ResumeState::new([7; 32], 1234);
+        for offset in [0, 1, 2, 5, 9, 20] {
+            state.mark(offset);
+        }
+        state.save(&path).unwrap();
+        assert_eq!(ResumeState::load(&path), Some(state.clone()));
+        state.mark(3);
+        state.save(&path).unwrap();
+        assert_eq!(ResumeState::load(&path), Some(state));
+        ResumeState::remove(&path);
+        assert_eq!(ResumeState::load(&path), None);
+    }
+
+    #[test]
+    fn truncated_state_is_ignored() {
+        let path = state_path("truncated");
+        write(&path, [0; HASH_SIZE + 4]).unwrap();
+        assert_eq!(ResumeState::load(&path), None);
+        ResumeState::remove(&path);
+    }
+
+    #[test]
+    fn only_plain_single_files_with_a_hash_are_resumable() {
+        let mut metadata = FileMetadata::from_path("file", &std::fs::metadata(".").unwrap());
+        assert!(!ResumeState::is_resumable(&metadata));
+        metadata.hash = Some([2; 32]);
+        assert!(ResumeState::is_resumable(&metadata));
+        assert!(ResumeState::new([2; 32], 10).matches(&metadata, 10));
+        assert!(!ResumeState::new([2; 32], 10).matches(&metadata, 11));
+        metadata.compression = Compression::Zstd;
+        assert!(!ResumeState::is_resumable(&metadata));
+    }
+}
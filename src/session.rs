@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use rand::Rng;
+
+/// The id of a session that hasn't joined a transfer yet
+pub const SESSION_UNBOUND: u32 = 0;
+
+///
+/// One transfer.  Every frame carries the session id so frames from another pair of
+/// machines in the room, or from an earlier run, can be told apart and ignored.  Whoever
+/// speaks first picks the id and the other side joins it when it sees their first frame.
+///
+pub struct Session {
+    id: AtomicU32,
+    next_sequence: AtomicU32,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        return Self::with_id(SESSION_UNBOUND);
+    }
+
+    pub fn random() -> Self {
+        return Self::with_id(rand::thread_rng().gen_range(1..=u32::MAX));
+    }
+
+    fn with_id(id: u32) -> Self {
+        return Self {
+            id: AtomicU32::new(id),
+            next_sequence: AtomicU32::new(0),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        return self.id.load(Ordering::Acquire);
+    }
+
+    pub fn is_bound(&self) -> bool {
+        return self.id() != SESSION_UNBOUND;
+    }
+
+    ///
+    /// Does a frame from session `id` belong to us?  An unbound session joins the first one
+    /// it's asked about.
+    ///
+    pub fn accepts(&self, id: u32) -> bool {
+        return match self.id.compare_exchange(SESSION_UNBOUND, id, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => true,
+            Err(current) => current == id,
+        };
+    }
+
+    pub fn next_sequence(&self) -> u32 {
+        return self.next_sequence.fetch_add(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,360 @@
+#[cfg(feature = "rxing")]
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use image::GrayImage;
+use qrcode::bits::Bits;
+use qrcode::{Color, EcLevel, QrCode, Version};
+use quircs::Quirc;
+#[cfg(feature = "rxing")]
+use rxing::{BarcodeFormat, DecodeHintType, DecodeHintValue, DecodingHintDictionary, EncodeHintType, EncodeHintValue, EncodingHintDictionary, MultiFormatWriter, Writer};
+#[cfg(feature = "rxing")]
+use rxing::datamatrix::encoder::SymbolShapeHint;
+
+use crate::handshake::{FEATURE_AZTEC, FEATURE_DATA_MATRIX};
+
+///
+/// Square ECC 200 Data Matrix sizes, modules a side and data codewords.
+///
+#[cfg(feature = "rxing")]
+const DATA_MATRIX_SIZES: [(u32, usize); 24] = [
+    (10, 3), (12, 5), (14, 8), (16, 12), (18, 18), (20, 22), (22, 30), (24, 36),
+    (26, 44), (32, 62), (36, 86), (40, 114), (44, 144), (48, 174), (52, 204), (64, 280),
+    (72, 368), (80, 456), (88, 576), (96, 696), (104, 816), (120, 1050), (132, 1304), (144, 1558),
+];
+
+/// The most layers a full range Aztec code has
+#[cfg(feature = "rxing")]
+const AZTEC_LAYERS: i16 = 32;
+
+///
+/// A square code's modules, a row at a time.
+///
+pub struct Symbol {
+    pub version: i16,
+    pub width: usize,
+    light: Vec<bool>,
+}
+
+impl Symbol {
+    pub fn is_light(&self, x: usize, y: usize) -> bool {
+        return self.light[y * self.width + x];
+    }
+}
+
+///
+/// A kind of code frames are shown in.  Sizes are numbered from 1 like QR versions, and
+/// whatever the kind calls them.
+///
+pub trait Symbology: Send {
+    fn max_version(&self) -> i16;
+
+    ///
+    /// How many modules a side a code this size is.
+    ///
+    fn modules(&self, version: i16) -> u32;
+
+    ///
+    /// How many bytes of frame a code this size holds.
+    ///
+    fn payload(&self, version: i16, ec_level: EcLevel) -> usize;
+
+    ///
+    /// Modules of light needed around a code to find it.
+    ///
+    fn quiet_zone(&self) -> u32;
+
+    ///
+    /// A code holding `data`, the size asked for if it fits and the kind lets us pick,
+    /// otherwise the smallest that does.
+    ///
+    fn encode(&self, data: &[u8], version: Option<i16>, ec_level: EcLevel) -> Result<Symbol, String>;
+
+    ///
+    /// What's in every code of this kind in the image, or why it couldn't be read.
+    ///
+    fn decode(&mut self, image: &GrayImage) -> Vec<Result<Vec<u8>, String>>;
+}
+
+///
+/// The kinds of code a transfer can be shown in.  QR is read by every build, the others are
+/// advertised as features.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodeKind {
+    #[default]
+    Qr,
+    DataMatrix,
+    Aztec,
+}
+
+impl CodeKind {
+    pub fn feature(&self) -> u32 {
+        return match self {
+            CodeKind::Qr => 0,
+            CodeKind::DataMatrix => FEATURE_DATA_MATRIX,
+            CodeKind::Aztec => FEATURE_AZTEC,
+        };
+    }
+
+    ///
+    /// The kinds this build can draw and read.
+    ///
+    pub fn available() -> Vec<CodeKind> {
+        let mut available = vec![CodeKind::Qr];
+        if cfg!(feature = "rxing") {
+            available.extend([CodeKind::DataMatrix, CodeKind::Aztec]);
+        }
+        return available;
+    }
+
+    ///
+    /// The features for every kind this build reads.
+    ///
+    pub fn readable() -> u32 {
+        return CodeKind::available().iter().fold(0, |features, kind| features | kind.feature());
+    }
+
+    ///
+    /// The kind to show given what we'd like and the features both sides agreed on.  QR is
+    /// what everyone reads.
+    ///
+    pub fn choose(preferred: CodeKind, features: u32) -> CodeKind {
+        if features & preferred.feature() == preferred.feature() {
+            return preferred;
+        }
+        return CodeKind::Qr;
+    }
+
+    ///
+    /// Draws and reads this kind, if it's built in.
+    ///
+    pub fn symbology(&self) -> Result<Box<dyn Symbology>, String> {
+        return match self {
+            CodeKind::Qr => Ok(Box::new(Qr::default())),
+            #[cfg(feature = "rxing")]
+            CodeKind::DataMatrix => Ok(Box::new(DataMatrix {})),
+            #[cfg(feature = "rxing")]
+            CodeKind::Aztec => Ok(Box::new(Aztec {})),
+            #[cfg(not(feature = "rxing"))]
+            _ => Err(format!("This piccp can't draw {} codes, it needs building with --features rxing", self)),
+        };
+    }
+}
+
+impl Display for CodeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        return f.write_str(match self {
+            CodeKind::Qr => "qr",
+            CodeKind::DataMatrix => "datamatrix",
+            CodeKind::Aztec => "aztec",
+        });
+    }
+}
+
+impl FromStr for CodeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "qr" => Ok(CodeKind::Qr),
+            "datamatrix" => Ok(CodeKind::DataMatrix),
+            "aztec" => Ok(CodeKind::Aztec),
+            _ => Err(format!("{} isn't one of qr, datamatrix or aztec", s)),
+        };
+    }
+}
+
+#[derive(Default)]
+pub struct Qr {
+    decoder: Quirc,
+}
+
+impl Symbology for Qr {
+    fn max_version(&self) -> i16 {
+        return 40;
+    }
+
+    fn modules(&self, version: i16) -> u32 {
+        return 17 + 4 * version as u32;
+    }
+
+    fn payload(&self, version: i16, ec_level: EcLevel) -> usize {
+        let bits = Bits::new(Version::Normal(version)).max_len(ec_level).unwrap_or(0);
+        // frames are binary so they're in byte mode, which starts with a mode and a length
+        let header = 4 + if version < 10 { 8 } else { 16 };
+        return bits.saturating_sub(header) / 8;
+    }
+
+    fn quiet_zone(&self) -> u32 {
+        return 4;
+    }
+
+    fn encode(&self, data: &[u8], version: Option<i16>, ec_level: EcLevel) -> Result<Symbol, String> {
+        let code = version
+            .and_then(|version| QrCode::with_version(data, Version::Normal(version), ec_level).ok())
+            .map_or_else(|| QrCode::with_error_correction_level(data, ec_level), Ok)
+            .map_err(|err| err.to_string())?;
+        let version = match code.version() {
+            Version::Normal(version) | Version::Micro(version) => version,
+        };
+        return Ok(Symbol {
+            version,
+            width: code.width(),
+            light: code.to_colors().into_iter().map(|color| color == Color::Light).collect(),
+        });
+    }
+
+    fn decode(&mut self, image: &GrayImage) -> Vec<Result<Vec<u8>, String>> {
+        return self.decoder.identify(image.width() as usize, image.height() as usize, image)
+            .map(|code| {
+                return code.map_err(|err| format!("{:?}", err))?
+                    .decode()
+                    .map(|data| data.payload)
+                    .map_err(|err| format!("{:?}", err));
+            })
+            .collect();
+    }
+}
+
+///
+/// Square ECC 200 codes.  The error correction is fixed, and they pick their own size.
+///
+#[cfg(feature = "rxing")]
+pub struct DataMatrix {}
+
+#[cfg(feature = "rxing")]
+impl Symbology for DataMatrix {
+    fn max_version(&self) -> i16 {
+        return DATA_MATRIX_SIZES.len() as i16;
+    }
+
+    fn modules(&self, version: i16) -> u32 {
+        return DATA_MATRIX_SIZES[version as usize - 1].0;
+    }
+
+    fn payload(&self, version: i16, _ec_level: EcLevel) -> usize {
+        // binary goes in a base 256 run, which starts with a latch and a length of one or two
+        let codewords = DATA_MATRIX_SIZES[version as usize - 1].1;
+        return if codewords - 2 <= 249 { codewords - 2 } else { codewords - 3 };
+    }
+
+    fn quiet_zone(&self) -> u32 {
+        return 1;
+    }
+
+    fn encode(&self, data: &[u8], _version: Option<i16>, _ec_level: EcLevel) -> Result<Symbol, String> {
+        let mut hints = EncodingHintDictionary::new();
+        hints.insert(EncodeHintType::DATA_MATRIX_SHAPE, EncodeHintValue::DataMatrixShape(SymbolShapeHint::FORCE_SQUARE));
+        return rxing_encode(self, data, BarcodeFormat::DATA_MATRIX, hints);
+    }
+
+    fn decode(&mut self, image: &GrayImage) -> Vec<Result<Vec<u8>, String>> {
+        return rxing_decode(image, BarcodeFormat::DATA_MATRIX);
+    }
+}
+
+///
+/// Full range Aztec codes, the error correction levels are 23, 33, 50 and 66 percent.  They
+/// pick their own number of layers.
+///
+#[cfg(feature = "rxing")]
+pub struct Aztec {}
+
+#[cfg(feature = "rxing")]
+impl Aztec {
+    fn ec_percent(ec_level: EcLevel) -> usize {
+        return match ec_level {
+            EcLevel::L => 23,
+            EcLevel::M => 33,
+            EcLevel::Q => 50,
+            EcLevel::H => 66,
+        };
+    }
+}
+
+#[cfg(feature = "rxing")]
+impl Symbology for Aztec {
+    fn max_version(&self) -> i16 {
+        return AZTEC_LAYERS;
+    }
+
+    fn modules(&self, version: i16) -> u32 {
+        // reference grid lines every 16 modules out from the centre
+        let base = 14 + 4 * version as u32;
+        return base + 1 + 2 * ((base / 2 - 1) / 15);
+    }
+
+    fn payload(&self, version: i16, ec_level: EcLevel) -> usize {
+        let layers = version as usize;
+        let bits = (88 + 16 * layers) * layers;
+        let codeword = match layers {
+            1..=2 => 6,
+            3..=8 => 8,
+            9..=22 => 10,
+            _ => 12,
+        };
+        let data = bits * (100 - Aztec::ec_percent(ec_level)) / 100;
+        // a bit of each codeword can go to stuffing, and binary starts with a shift and a length
+        return (data * (codeword - 1) / codeword).saturating_sub(21) / 8;
+    }
+
+    fn quiet_zone(&self) -> u32 {
+        return 1;
+    }
+
+    fn encode(&self, data: &[u8], _version: Option<i16>, ec_level: EcLevel) -> Result<Symbol, String> {
+        let mut hints = EncodingHintDictionary::new();
+        hints.insert(EncodeHintType::ERROR_CORRECTION, EncodeHintValue::ErrorCorrection(Aztec::ec_percent(ec_level).to_string()));
+        return rxing_encode(self, data, BarcodeFormat::AZTEC, hints);
+    }
+
+    fn decode(&mut self, image: &GrayImage) -> Vec<Result<Vec<u8>, String>> {
+        return rxing_decode(image, BarcodeFormat::AZTEC);
+    }
+}
+
+///
+/// rxing takes text, but ISO-8859-1 is a character for each byte so the bytes come back as
+/// they went in.
+///
+#[cfg(feature = "rxing")]
+fn rxing_encode(symbology: &dyn Symbology, data: &[u8], format: BarcodeFormat, mut hints: EncodingHintDictionary) -> Result<Symbol, String> {
+    let text: String = data.iter().map(|byte| *byte as char).collect();
+    hints.insert(EncodeHintType::CHARACTER_SET, EncodeHintValue::CharacterSet("ISO-8859-1".to_string()));
+    let matrix = MultiFormatWriter::default()
+        .encode_with_hints(&text, &format, 0, 0, &hints)
+        .map_err(|err| err.to_string())?;
+    let width = matrix.getWidth() as usize;
+    let version = (1..=symbology.max_version())
+        .find(|version| symbology.modules(*version) as usize >= width)
+        .unwrap_or(symbology.max_version());
+    let mut light = Vec::with_capacity(width * width);
+    for y in 0..width {
+        for x in 0..width {
+            // set bits are dark
+            light.push(!matrix.get(x as u32, y as u32));
+        }
+    }
+    return Ok(Symbol {
+        version,
+        width,
+        light,
+    });
+}
+
+#[cfg(feature = "rxing")]
+fn rxing_decode(image: &GrayImage, format: BarcodeFormat) -> Vec<Result<Vec<u8>, String>> {
+    let mut hints = DecodingHintDictionary::new();
+    hints.insert(DecodeHintType::POSSIBLE_FORMATS, DecodeHintValue::PossibleFormats(HashSet::from([format])));
+    return match rxing::helpers::detect_multiple_in_luma_with_hints(image.as_raw().clone(), image.width(), image.height(), &mut hints) {
+        Ok(results) => results.iter()
+            .map(|result| result.getText().chars()
+                .map(|c| u8::try_from(c).map_err(|_| format!("{} isn't a byte", c)))
+                .collect())
+            .collect(),
+        // finding nothing is an error too
+        Err(_) => Vec::new(),
+    };
+}
@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+
+/// Waits double each time they run out, up to this many times
+const MAX_BACKOFF_SHIFT: u32 = 3;
+
+///
+/// Where the time comes from, so the timing can be driven by hand.
+///
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        return Instant::now();
+    }
+}
+
+///
+/// Notices when the other side has gone quiet for too long.  Each time it runs out in a row
+/// the wait doubles, so a receiver that's looking elsewhere isn't flooded with frames.
+///
+pub struct Silence<C: Clock = SystemClock> {
+    clock: C,
+    timeout: Duration,
+    since: Instant,
+    attempts: u32,
+}
+
+impl Silence {
+    pub fn new(timeout: Duration) -> Self {
+        return Self::with_clock(SystemClock, timeout);
+    }
+}
+
+impl<C: Clock> Silence<C> {
+    ///
+    /// A timeout of zero never runs out.
+    ///
+    pub fn with_clock(clock: C, timeout: Duration) -> Self {
+        let since = clock.now();
+        return Self {
+            clock,
+            timeout,
+            since,
+            attempts: 0,
+        };
+    }
+
+    ///
+    /// We heard something, start waiting again.
+    ///
+    pub fn reset(&mut self) {
+        self.since = self.clock.now();
+        self.attempts = 0;
+    }
+
+    ///
+    /// Has it been quiet for too long?  Returns how many times in a row it has been.
+    ///
+    pub fn expired(&mut self) -> Option<u32> {
+        if self.timeout.is_zero() {
+            return None;
+        }
+        let now = self.clock.now();
+        let wait = self.timeout * (1 << self.attempts.min(MAX_BACKOFF_SHIFT));
+        if now.duration_since(self.since) < wait {
+            return None;
+        }
+        self.since = now;
+        self.attempts += 1;
+        return Some(self.attempts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct ManualClock {
+        now: Instant,
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            return self.now;
+        }
+    }
+
+    fn silence(timeout: Duration) -> Silence<ManualClock> {
+        return Silence::with_clock(ManualClock { now: Instant::now() }, timeout);
+    }
+
+    fn wait(silence: &mut Silence<ManualClock>, duration: Duration) {
+        silence.clock.now += duration;
+    }
+
+    #[test]
+    fn nothing_expires_before_the_timeout() {
+        let mut silence = silence(TIMEOUT);
+        assert_eq!(silence.expired(), None);
+        wait(&mut silence, TIMEOUT - Duration::from_millis(1));
+        assert_eq!(silence.expired(), None);
+        wait(&mut silence, Duration::from_millis(1));
+        assert_eq!(silence.expired(), Some(1));
+        assert_eq!(silence.expired(), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut silence = silence(TIMEOUT);
+        for attempt in 0..MAX_BACKOFF_SHIFT + 3 {
+            let wait_for = TIMEOUT * (1 << attempt.min(MAX_BACKOFF_SHIFT));
+            wait(&mut silence, wait_for - Duration::from_millis(1));
+            assert_eq!(silence.expired(), None, "attempt {}", attempt);
+            wait(&mut silence, Duration::from_millis(1));
+            assert_eq!(silence.expired(), Some(attempt + 1), "attempt {}", attempt);
+        }
+    }
+
+    #[test]
+    fn reset_starts_the_backoff_over() {
+        let mut silence = silence(TIMEOUT);
+        for _ in 0..2 {
+            wait(&mut silence, TIMEOUT * 8);
+            silence.expired();
+        }
+        wait(&mut silence, TIMEOUT * 3);
+        silence.reset();
+        wait(&mut silence, TIMEOUT);
+        assert_eq!(silence.expired(), Some(1));
+    }
+
+    #[test]
+    fn zero_never_expires() {
+        let mut silence = silence(Duration::ZERO);
+        for _ in 0..3 {
+            wait(&mut silence, Duration::from_secs(3600));
+            assert_eq!(silence.expired(), None);
+        }
+    }
+}
@@ -1,36 +1,549 @@
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::select;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::interval;
 
 use crate::{Frame, Log};
+use crate::frame::ParsedFrame;
+use crate::fountain::{SymbolCollector, SymbolStream};
+use crate::cipher::{Cipher, TAG_SIZE};
+use crate::compression::{BlockingSource, CompressedSource};
+use crate::handshake::{Capabilities, Compression, FEATURE_ENCRYPTION, FEATURE_RGB};
+use crate::hash::{ContentHasher, Hash, to_hex};
+use crate::identity::{Identity, Signature};
+use crate::metadata::FileMetadata;
+use crate::pairing::{Pairing, PublicKey};
+use crate::reason::Reason;
+use crate::resume::ResumeState;
+use crate::session::Session;
+use crate::symbology::CodeKind;
+use crate::timer::Silence;
 use crate::message::Message;
 
 pub trait SegmentSource: Send {
     fn size(&self) -> Option<usize> {return None;}
+    fn metadata(&self) -> FileMetadata {
+        return FileMetadata {
+            size: self.size().map(|size| size as u64),
+            ..FileMetadata::default()
+        };
+    }
     fn read_segment(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<usize>;
 }
 
 pub trait SegmentSourceFactory: Send {
-    type SegmentSourceType: SegmentSource;
-    fn create_segment_source(&self) -> Self::SegmentSourceType;
+    type SegmentSourceType: SegmentSource + 'static;
+    fn create_segment_source(&self) -> std::io::Result<Self::SegmentSourceType>;
+}
+
+/// How often senders that can't tell whether the metadata arrived repeat it, in frames
+const METADATA_INTERVAL: usize = 16;
+/// Why we stop when the other side didn't send a pairing key
+const NO_PAIRING: &str = "The other side can't pair, give both sides --no-pairing to go on without it";
+/// Why an interrupted transfer won't be picked up again
+const NOT_RESUMABLE: &str = "Compressed data can't be resumed if we're interrupted, a broadcasting sender needs --compression none for that";
+
+///
+/// The segments the sender has read but the receiver hasn't acknowledged yet.
+///
+struct SendWindow {
+    size: usize,
+    /// Where we started, after whatever an earlier transfer got across
+    start: usize,
+    base: usize,
+    next_read: usize,
+    end: Option<usize>,
+    cursor: Option<usize>,
+    segments: BTreeMap<usize, Vec<u8>>,
+    received: BTreeSet<usize>,
+    /// Only what's read from the start can be hashed
+    hasher: Option<ContentHasher>,
+    hash: Option<Hash>,
+}
+
+impl SendWindow {
+    fn new(size: usize) -> Self {
+        return Self {
+            size,
+            start: 0,
+            base: 0,
+            next_read: 0,
+            end: None,
+            cursor: None,
+            segments: BTreeMap::new(),
+            received: BTreeSet::new(),
+            hasher: Some(ContentHasher::new()),
+            hash: None,
+        }
+    }
+
+    ///
+    /// Skip what the receiver already has from an earlier transfer.
+    ///
+    fn resume(&mut self, offset: usize) {
+        self.start = offset;
+        self.base = offset;
+        self.next_read = offset;
+        self.hasher = None;
+    }
+
+    ///
+    /// Take in a CTS.  One from before the last we took in, seen late, says nothing new.
+    ///
+    fn acknowledge(&mut self, base: usize, received: &[u8]) {
+        if base < self.base {
+            return;
+        }
+        self.base = base;
+        self.segments = self.segments.split_off(&self.base);
+        self.received.clear();
+        for i in 0..received.len() * 8 {
+            if received[i / 8] & (1 << (i % 8)) != 0 {
+                self.received.insert(self.base + i);
+            }
+        }
+    }
+
+    async fn fill<S: SegmentSource + 'static>(&mut self, input: &BlockingSource<S>, segment_size: usize) -> std::io::Result<()> {
+        while self.end.is_none() && self.next_read < self.base + self.size {
+            let segment = input.read_segment(self.next_read, segment_size).await?;
+            if segment.is_empty() {
+                self.end = Some(self.next_read);
+                self.hash = self.hasher.as_mut().map(|hasher| hasher.finish());
+            } else {
+                if let Some(hasher) = &mut self.hasher {
+                    hasher.update(&segment);
+                }
+                self.segments.insert(self.next_read, segment);
+                self.next_read += 1;
+            }
+        }
+        return Ok(());
+    }
+
+    fn has_progress(&self) -> bool {
+        return self.base > self.start || !self.received.is_empty();
+    }
+
+    fn is_complete(&self) -> bool {
+        return match self.end {
+            Some(end) => self.base >= end,
+            None => false
+        };
+    }
+
+    ///
+    /// The next segment in the window the receiver is still missing, cycling round-robin.
+    ///
+    fn next_missing(&mut self) -> Option<(usize, &[u8])> {
+        let received = &self.received;
+        let after = self.cursor.map_or(self.base, |c| c + 1);
+        let offset = self.segments.range(after..)
+            .chain(self.segments.range(..after))
+            .map(|(offset, _)| *offset)
+            .find(|offset| !received.contains(offset))?;
+        self.cursor = Some(offset);
+        return Some((offset, &self.segments[&offset]));
+    }
+}
+
+///
+/// Where a windowed receiver is up to.  Frames move it along by what they say rather than
+/// when they were sent, so one that's seen twice, or an old one that turns up late, changes
+/// nothing.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ReceivePhase {
+    /// Not receiving anything, only passing what the receiver shows on to our sender
+    Relaying,
+    /// Showing our HELLO until the sender answers
+    Negotiating,
+    /// Agreed, until our operator has checked the pairing code
+    Pairing,
+    /// Showing CTS until the sender says what it's sending
+    Waiting,
+    /// Showing CTS until the sender is done
+    Receiving,
+    /// The sender is done, until what we wrote has been checked
+    Verifying,
+}
+
+///
+/// What a windowed receiver does about a frame, once `step` has said where it leaves it.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    /// Tell our sender about the receiver's HELLO
+    PassHello(Capabilities, Option<PublicKey>),
+    /// Tell our sender what the receiver has, from a CTS or RESUME
+    PassWindow(usize, Vec<u8>, Option<Hash>),
+    /// Tell our sender what the receiver wrote, which is the end of it
+    PassVerified(Hash),
+    /// Go with what the sender agreed to
+    Agree(Capabilities),
+    /// Show our operator the code for the sender's pairing key
+    Pair(PublicKey),
+    /// Show the sender where we are
+    ShowProgress,
+    /// Take the sender's description of what it's sending
+    Describe(FileMetadata),
+    /// Write the segment at this offset, if it's one we're waiting for
+    Accept(usize),
+    /// Check what was written against the sender's hash
+    Verify(Option<Hash>, Option<Signature>),
+    /// The other side stopped
+    Stopped(Reason, String),
+    /// We can't go on
+    Stop(Reason, String),
+    Log(String),
+}
+
+impl ReceivePhase {
+    ///
+    /// Where a frame leaves us and what to do about it, given what we can do and whether
+    /// the operators compare pairing codes.  Anything a phase isn't waiting for is ignored.
+    ///
+    fn step(self, frame: &ParsedFrame, capabilities: &Capabilities, pairing: bool) -> (Self, Vec<Action>) {
+        use ReceivePhase::*;
+        return match (self, frame) {
+            // what the receiver on the other side says back to our sender
+            (Relaying, ParsedFrame::Hello(peer, key)) => (self, vec![Action::PassHello(*peer, *key)]),
+            (Relaying, ParsedFrame::Cts { segment_offset, received }) => (self, vec![Action::PassWindow(*segment_offset, received.to_vec(), None)]),
+            (Relaying, ParsedFrame::Resume { hash, segment_offset, received }) => (self, vec![Action::PassWindow(*segment_offset, received.to_vec(), Some(*hash))]),
+            (Relaying, ParsedFrame::Verified(hash)) => (self, vec![Action::PassVerified(*hash)]),
+            // a sender that can't agree with ours may answer with its own HELLO
+            (Negotiating, ParsedFrame::Hello(peer, _)) => match capabilities.negotiate(peer) {
+                Ok(_) => (self, Vec::new()),
+                Err(err) => (self, vec![Action::Stop(Reason::Incompatible, err)]),
+            },
+            (Negotiating, ParsedFrame::HelloAck(agreed, key)) => match (capabilities.negotiate(agreed), key.filter(|_| pairing)) {
+                (Err(err), _) => (self, vec![Action::Stop(Reason::Incompatible, err)]),
+                (Ok(_), Some(key)) => (Pairing, vec![Action::Agree(*agreed), Action::Pair(key)]),
+                (Ok(_), None) if pairing => (self, vec![Action::Stop(Reason::Incompatible, NO_PAIRING.to_string())]),
+                (Ok(_), None) => (Waiting, vec![
+                    Action::Agree(*agreed),
+                    Action::Log("Not pairing, there's no telling who the sender is".to_string()),
+                    Action::ShowProgress,
+                ]),
+            },
+            (Waiting, ParsedFrame::Metadata(metadata)) => (Receiving, vec![Action::Describe(metadata.clone())]),
+            (Waiting, ParsedFrame::Segment { segment_offset, .. }) => (self, vec![Action::Log(format!("Waiting for metadata, dropping segment {}", segment_offset))]),
+            (Receiving, ParsedFrame::Segment { segment_offset, .. }) => (self, vec![Action::Accept(*segment_offset)]),
+            (Waiting | Receiving, ParsedFrame::Done(hash, signature)) => (Verifying, vec![Action::Verify(*hash, *signature)]),
+            (_, ParsedFrame::Symbol { .. }) => (self, vec![Action::Log("Unexpected symbol, is the sender using --fountain?".to_string())]),
+            (_, ParsedFrame::Key(_)) => (self, vec![Action::Log("That's a key, import it with import-key".to_string())]),
+            (_, ParsedFrame::Abort(reason, text) | ParsedFrame::Error(reason, text)) => (self, vec![Action::Stopped(*reason, text.clone())]),
+            // answers to things we've moved past, from before we're ready for them, or what
+            // we showed ourselves seen again
+            _ => (self, Vec::new()),
+        };
+    }
+}
+
+///
+/// Out of order segments held by the receiver until the ones before them arrive.
+///
+struct ReceiveWindow {
+    size: usize,
+    base: usize,
+    pending: BTreeMap<usize, Frame>,
+}
+
+impl ReceiveWindow {
+    fn new(size: usize) -> Self {
+        return Self::starting_at(size, 0);
+    }
+
+    fn starting_at(size: usize, base: usize) -> Self {
+        return Self {
+            size,
+            base,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// Accept a segment, returning the segments that are now ready to be written in order or
+    /// `None` if the segment was a duplicate or outside the window.
+    ///
+    fn accept(&mut self, offset: usize, frame: Frame) -> Option<Vec<Frame>> {
+        if offset < self.base || offset >= self.base + self.size || self.pending.contains_key(&offset) {
+            return None;
+        }
+        self.pending.insert(offset, frame);
+        let mut ready = Vec::new();
+        while let Some(frame) = self.pending.remove(&self.base) {
+            ready.push(frame);
+            self.base += 1;
+        }
+        return Some(ready);
+    }
+
+    fn received(&self) -> Vec<u8> {
+        let mut received = vec![0u8; (self.size + 7) / 8];
+        for offset in self.pending.keys() {
+            let i = offset - self.base;
+            received[i / 8] |= 1 << (i % 8);
+        }
+        return received;
+    }
+}
+
+///
+/// Tracks which segments a broadcast receiver has seen, in any order.
+///
+struct BroadcastState {
+    segment_count: usize,
+    received: BTreeSet<usize>,
+}
+
+impl BroadcastState {
+    fn new() -> Self {
+        return Self {
+            segment_count: 0,
+            received: BTreeSet::new(),
+        }
+    }
+
+    ///
+    /// Record a segment, returning false if it had already been seen.
+    ///
+    fn accept(&mut self, segment_offset: usize, segment_count: usize) -> bool {
+        self.segment_count = self.segment_count.max(segment_count);
+        return self.received.insert(segment_offset);
+    }
+
+    fn is_complete(&self) -> bool {
+        return self.segment_count > 0 && self.received.len() >= self.segment_count;
+    }
+}
+
+///
+/// Take a segment out of its encryption, rebuilding its frame around the plaintext.
+///
+fn open_segment(cipher: &mut Cipher, session: &Session, encrypted: bool, frame: Frame) -> Result<Frame, String> {
+    if !encrypted {
+        return Ok(frame);
+    }
+    return match frame.parsed() {
+        ParsedFrame::Segment { segment_offset, segment_count, data } => {
+            let data = cipher.open_segment(session.id(), segment_offset, data)?;
+            Ok(Frame::new_segment(session, segment_offset, segment_count, data))
+        }
+        _ => Ok(frame),
+    };
+}
+
+///
+/// Encrypt the body of a frame that gives something away about the data, like its name or
+/// hash, when we have a passphrase.
+///
+fn seal_frame(cipher: &mut Cipher, session: &Session, frame: Frame) -> Result<Frame, String> {
+    if !cipher.is_enabled() {
+        return Ok(frame);
+    }
+    let sealed = cipher.seal_frame(session.id(), frame.get_type(), frame.get_sequence(), frame.body())?;
+    return Ok(frame.to_sealed(&sealed));
+}
+
+///
+/// Take a SEALED frame out of its encryption.  With a passphrase the frames that would have
+/// been sealed are refused in the clear, or anyone showing a code could turn encryption off.
+///
+fn open_frame(cipher: &mut Cipher, session: &Session, frame: Frame) -> Result<Frame, String> {
+    return match frame.parsed() {
+        ParsedFrame::Sealed { frame_type, body } => {
+            let body = cipher.open_frame(session.id(), frame_type, frame.get_sequence(), body)?;
+            frame.to_opened(frame_type, &body).map_err(|err| format!("Frame {}: {}", frame.get_sequence(), err))
+        }
+        ParsedFrame::Metadata(_) | ParsedFrame::Done(..) | ParsedFrame::Verified(_) | ParsedFrame::Resume { .. } if cipher.is_enabled() => {
+            Err("The other side isn't encrypting, refusing to go on in the clear".to_string())
+        }
+        _ => Ok(frame),
+    };
+}
+
+///
+/// DONE, signed when we have an identity and know what we sent.
+///
+fn done_frame(session: &Session, hash: Option<Hash>, identity: &Option<Identity>) -> Frame {
+    return match (hash, sign(hash, identity)) {
+        (Some(hash), Some(signature)) => Frame::new_signed_done(session, &hash, &signature),
+        (hash, _) => Frame::new_done(session, hash.as_ref()),
+    };
+}
+
+fn sign(hash: Option<Hash>, identity: &Option<Identity>) -> Option<Signature> {
+    return hash.zip(identity.as_ref()).map(|(hash, identity)| identity.sign(&hash));
+}
+
+///
+/// Whether the sender is encrypting.  The metadata is only trusted to say so once it has
+/// come through `open_frame`.
+///
+fn check_encryption(cipher: &Cipher, metadata: &FileMetadata) -> Result<bool, String> {
+    if cipher.is_enabled() && !metadata.encrypted {
+        return Err("The sender isn't encrypting, refusing to go on in the clear".to_string());
+    }
+    return Ok(metadata.encrypted);
+}
+
+///
+/// What the sender tells the user once the receiver says what it wrote.
+///
+fn delivery_report(sent: Option<Hash>, written: Hash) -> String {
+    return match sent {
+        Some(sent) if sent == written => "Delivered and verified".to_string(),
+        Some(sent) => format!("Delivered but corrupted: we sent {} and the receiver wrote {}", to_hex(&sent), to_hex(&written)),
+        None => format!("Delivered, the receiver wrote {}", to_hex(&written)),
+    };
+}
+
+///
+/// Show the peer why we've stopped, and tell the user.
+///
+fn stop(frame_handler: &UnboundedSender<Message>, session: &Session, reason: Reason, text: String) {
+    frame_handler.send(Message::WriteData(Frame::new_abort(session, reason, &text))).unwrap();
+    frame_handler.send(Message::Failed(reason, text)).unwrap();
+}
+
+///
+/// Show frames, each in a lane of its own when there's more than one.
+///
+fn show(frame_handler: &UnboundedSender<Message>, mut frames: Vec<Frame>) {
+    if frames.len() == 1 {
+        frame_handler.send(Message::WriteData(frames.remove(0))).unwrap();
+    } else if !frames.is_empty() {
+        frame_handler.send(Message::WriteLanes(frames)).unwrap();
+    }
+}
+
+///
+/// What the sender tells the user when the receiver has gone quiet.
+///
+fn waiting_hint(started: bool, done: bool, attempt: u32) -> String {
+    let waiting = if !started {
+        "Waiting for the receiver to answer"
+    } else if done {
+        "Waiting for the receiver to say what it wrote"
+    } else {
+        "Waiting for the receiver to acknowledge"
+    };
+    return format!("{}, can its camera see this screen? ({})", waiting, attempt);
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+    pub fragment_size: u16,
+    pub window_size: u16,
+    pub frame_interval: u32,
+    pub broadcast: bool,
+    pub fountain: bool,
+    pub max_payload: u16,
+    pub compression: Compression,
+    pub passphrase: Option<String>,
+    /// Who signs what we send
+    pub identity: Option<Identity>,
+    /// Where a receiver keeps track of what it has written
+    pub resume_file: Option<PathBuf>,
+    /// How long to wait in milliseconds without hearing from the other side before asking again
+    pub timeout: u32,
+    /// How many codes the senders show at once
+    pub lanes: usize,
+    /// Whether we can lay three frames over each other in each code, once the other side
+    /// says it reads them
+    pub rgb: bool,
+    /// The kind of code we'd like to show once the other side says it can read it
+    pub symbology: CodeKind,
+    /// Whether the operators compare pairing codes before any data moves
+    pub pairing: bool,
+}
+
+impl TransportOptions {
+    pub fn capabilities(&self) -> Capabilities {
+        // compressed data can't be resumed, so a receiver writing to a file asks for it as is
+        let compression = if self.resume_file.is_some() { Compression::None } else { self.compression };
+        let capabilities = Capabilities::new(self.max_payload, self.fragment_size, self.window_size, compression);
+        let encryption = if self.passphrase.is_some() { FEATURE_ENCRYPTION } else { 0 };
+        let rgb = if self.rgb { FEATURE_RGB } else { 0 };
+        // we read every kind of code we can draw
+        return Capabilities {
+            features: capabilities.features | CodeKind::readable() | encryption | rgb,
+            ..capabilities
+        };
+    }
+
+    ///
+    /// Switch to the kind of code we'd like if the other side can read it, saying so if it can't.
+    /// Returns whether the codes are in colour.
+    ///
+    fn show_codes(&self, frame_handler: &UnboundedSender<Message>, features: u32) -> bool {
+        let symbology = CodeKind::choose(self.symbology, features);
+        if symbology != self.symbology {
+            frame_handler.send(Message::Log(format!("The other side can't read {} codes, showing {}", self.symbology, symbology))).unwrap();
+        }
+        frame_handler.send(Message::Symbology(symbology)).unwrap();
+        let rgb = self.rgb && features & FEATURE_RGB != 0;
+        if self.rgb && !rgb {
+            frame_handler.send(Message::Log("The other side can't read codes in colour, showing them in black and white".to_string())).unwrap();
+        }
+        frame_handler.send(Message::Colour(rgb)).unwrap();
+        return rgb;
+    }
+
+    ///
+    /// How many frames go up at once, three to a code in colour.
+    ///
+    fn frames_shown(&self, rgb: bool) -> usize {
+        return if rgb { 3 * self.lanes.max(1) } else { self.lanes.max(1) };
+    }
+
+    pub fn timeout(&self) -> Duration {
+        return Duration::from_millis(self.timeout as u64);
+    }
+
+    ///
+    /// How much data goes in a fragment once encryption has taken its share.
+    ///
+    pub fn data_size(&self, fragment_size: u16) -> usize {
+        return if self.passphrase.is_some() {
+            (fragment_size as usize).saturating_sub(TAG_SIZE).max(1)
+        } else {
+            fragment_size as usize
+        };
+    }
 }
 
 #[derive(Clone)]
 pub struct Transport {
+    frame_handler: UnboundedSender<Message>,
     sender_tx: UnboundedSender<Message>,
-    receiver_tx: UnboundedSender<Message>
+    receiver_tx: UnboundedSender<Message>,
+    session: Arc<Session>,
+    log: Log
 }
 
 impl Transport {
     pub async fn new<I: 'static>(frame_handler: UnboundedSender<Message>,
                                  log: Log,
                                  segment_source_factory: I,
-                                 fragment_size: u16) -> Self
+                                 session: Session,
+                                 options: TransportOptions) -> Self
         where I: SegmentSourceFactory
     {
-        let sender_tx = Self::start_sender(frame_handler.clone(), segment_source_factory, fragment_size).await;
-        let receiver_tx = Self::start_receiver(frame_handler, log,sender_tx.clone()).await;
+        let session = Arc::new(session);
+        let sender_tx = Self::start_sender(frame_handler.clone(), segment_source_factory, session.clone(), options.clone()).await;
+        let receiver_tx = Self::start_receiver(frame_handler.clone(), log.clone(), sender_tx.clone(), session.clone(), options).await;
         return Self {
+            frame_handler,
             sender_tx,
-            receiver_tx
+            receiver_tx,
+            session,
+            log
         }
     }
 
@@ -39,95 +552,1087 @@ impl Transport {
     }
 
     pub fn receive_frame(&self, frame: Frame) {
-        self.receiver_tx.send(Message::ReceiveFrame(frame)).unwrap();
+        let joining = !self.session.is_bound();
+        if !self.session.accepts(frame.get_session()) {
+            self.log.log(format!("Ignoring frame from session {:08x}", frame.get_session()));
+            return;
+        }
+        if joining {
+            self.log.log(format!("Joined session {:08x}", frame.get_session()));
+        }
+        // the camera keeps going for a moment after we've stopped
+        let _ = self.receiver_tx.send(Message::ReceiveFrame(frame));
+    }
+
+    ///
+    /// Tell the peer what ended up in the output once it has been checked.
+    ///
+    pub fn verified(&self, hash: Hash) {
+        self.receiver_tx.send(Message::Verified(hash)).unwrap();
+    }
+
+    ///
+    /// The operator has checked the pairing code matches the other screen.
+    ///
+    pub fn pair(&self) {
+        self.sender_tx.send(Message::Paired).unwrap();
+        self.receiver_tx.send(Message::Paired).unwrap();
+    }
+
+    ///
+    /// Stop where we are and show the peer why.
+    ///
+    pub fn stop(&self, reason: Reason, text: String) {
+        // either may have finished already
+        let _ = self.sender_tx.send(Message::Donzo);
+        let _ = self.receiver_tx.send(Message::Donzo);
+        stop(&self.frame_handler, &self.session, reason, text);
     }
 
     pub fn send(&self) {
-        self.sender_tx.send(Message::SendFrame(0)).unwrap();
+        self.sender_tx.send(Message::SendWindow(0, Vec::new(), None)).unwrap();
     }
 
     async fn start_receiver(frame_handler: UnboundedSender<Message>,
                             log: Log,
-                            frame_sender: UnboundedSender<Message>) -> UnboundedSender<Message> {
-        let (tx, mut rx) = unbounded_channel();
+                            frame_sender: UnboundedSender<Message>,
+                            session: Arc<Session>,
+                            options: TransportOptions) -> UnboundedSender<Message> {
+        let (tx, rx) = unbounded_channel();
         let receiver_tx = tx.clone();
         tokio::spawn(async move {
-            let mut expected_segment_offset: usize = 0;
-            let mut expected_frame_sequence: usize = 0;
+            if options.fountain {
+                Self::receive_fountain(frame_handler, log, frame_sender, rx, receiver_tx, session, options).await;
+            } else if options.broadcast {
+                Self::receive_broadcast(frame_handler, log, frame_sender, rx, receiver_tx, session, options).await;
+            } else {
+                Self::receive_windowed(frame_handler, log, frame_sender, rx, receiver_tx, session, options).await;
+            }
+        });
+        return tx;
+    }
+
+    async fn receive_windowed(frame_handler: UnboundedSender<Message>,
+                              log: Log,
+                              frame_sender: UnboundedSender<Message>,
+                              mut rx: UnboundedReceiver<Message>,
+                              receiver_tx: UnboundedSender<Message>,
+                              session: Arc<Session>,
+                              options: TransportOptions) {
+        let capabilities = options.capabilities();
+        let mut cipher = Cipher::new(options.passphrase.clone());
+        let mut pairing = Pairing::new();
+        let mut phase = ReceivePhase::Relaying;
+        let mut segment_size = options.data_size(options.fragment_size);
+        // what an earlier transfer got across, until the sender says whether it's the same file
+        let mut earlier: Option<ResumeState> = None;
+        let mut metadata: Option<FileMetadata> = None;
+        let mut encrypted = false;
+        let mut window = ReceiveWindow::new(options.window_size as usize);
+        // the last thing the sender side was told, so the same code read again isn't passed on
+        let mut last_passed: Option<Frame> = None;
+        // nothing new from the sender for this long means it probably missed what we showed
+        let mut silence = Silence::new(options.timeout());
+        let ticker_tx = receiver_tx.clone();
+        let frame_interval = Duration::from_millis(options.frame_interval as u64);
+        tokio::spawn(async move {
+            let mut ticker = interval(frame_interval);
             loop {
-                match rx.recv().await.expect("No messages") {
-                    Message::ReceiveNextFrame => {
-                        frame_handler.send(Message::WriteData(Frame::new_cts(expected_segment_offset))).unwrap();
-                    }
-                    Message::ReceiveFrame(frame) => {
-                        if frame.get_sequence() == expected_frame_sequence {
-                            expected_frame_sequence += 1;
-                            if frame.is_cts() {
-                                expected_segment_offset = frame.get_segment_offset();
-                                frame_sender.send(Message::SendFrame(expected_segment_offset)).unwrap();
-                            } else if frame.is_done() {
-                                frame_handler.send(Message::WriteData(Frame::new_done())).unwrap();
+                ticker.tick().await;
+                if ticker_tx.send(Message::Tick).is_err() {
+                    return;
+                }
+            }
+        });
+        loop {
+            match rx.recv().await.expect("No messages") {
+                Message::ReceiveNextFrame => {
+                    if phase == ReceivePhase::Relaying {
+                        phase = ReceivePhase::Negotiating;
+                    }
+                    match phase {
+                        ReceivePhase::Negotiating => {
+                            frame_handler.send(Message::WriteData(Frame::new_hello(&session, &capabilities, options.pairing.then_some(pairing.public_key())))).unwrap();
+                        }
+                        ReceivePhase::Waiting | ReceivePhase::Receiving => {
+                            let frame = match &earlier {
+                                Some(earlier) => seal_frame(&mut cipher, &session, Frame::new_resume(&session, &earlier.hash, window.base, &window.received())),
+                                None => Ok(Frame::new_cts(&session, window.base, &window.received())),
+                            };
+                            let frame = match frame {
+                                Ok(frame) => frame,
+                                Err(err) => {
+                                    stop(&frame_handler, &session, Reason::Encryption, err);
+                                    let _ = frame_sender.send(Message::Donzo);
+                                    return;
+                                }
+                            };
+                            frame_handler.send(Message::WriteData(frame)).unwrap();
+                        }
+                        ReceivePhase::Relaying | ReceivePhase::Pairing | ReceivePhase::Verifying => {}
+                    }
+                }
+                Message::ReceiveFrame(frame) => {
+                    cipher.unlock(session.id()).await;
+                    let frame = match open_frame(&mut cipher, &session, frame) {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            stop(&frame_handler, &session, Reason::Encryption, err);
+                            let _ = frame_sender.send(Message::Donzo);
+                            return;
+                        }
+                    };
+                    let (next, actions) = phase.step(&frame.parsed(), &capabilities, options.pairing);
+                    phase = next;
+                    for action in actions {
+                        match action {
+                            Action::PassHello(..) | Action::PassWindow(..) if last_passed.as_ref().is_some_and(|last| last.parsed() == frame.parsed()) => {}
+                            Action::PassHello(peer, key) => {
+                                last_passed = Some(frame.clone());
+                                frame_sender.send(Message::Hello(peer, key)).unwrap();
+                            }
+                            Action::PassWindow(segment_offset, received, hash) => {
+                                last_passed = Some(frame.clone());
+                                frame_sender.send(Message::SendWindow(segment_offset, received, hash)).unwrap();
+                            }
+                            Action::PassVerified(hash) => {
+                                frame_sender.send(Message::Verified(hash)).unwrap();
                                 frame_handler.send(Message::Donzo).unwrap();
                                 receiver_tx.send(Message::Donzo).unwrap();
                                 frame_sender.send(Message::Donzo).unwrap();
-                            } else if frame.is_segment() {
-                                let offset = frame.get_segment_offset();
-                                if expected_segment_offset == offset {
-                                    frame_handler.send(Message::AppendToOutput(frame)).unwrap();
-                                    receiver_tx.send(Message::ReceiveNextFrame).unwrap();
-                                    expected_segment_offset += 1;
-                                } else {
-                                    log.log(format!("Unexpected segment {}", offset));
+                            }
+                            Action::Agree(agreed) => {
+                                silence.reset();
+                                options.show_codes(&frame_handler, agreed.features);
+                                segment_size = options.data_size(agreed.fragment_size);
+                                earlier = options.resume_file.as_deref()
+                                    .and_then(ResumeState::load)
+                                    .filter(|earlier| earlier.segment_size == segment_size);
+                                let base = earlier.as_ref().map_or(0, |earlier| earlier.base());
+                                window = ReceiveWindow::starting_at(agreed.window_size as usize, base);
+                                log.log(format!("Agreed on {}b fragments, window of {}", agreed.fragment_size, agreed.window_size));
+                            }
+                            Action::Pair(key) => {
+                                frame_handler.send(Message::Pairing(pairing.pair(&key))).unwrap();
+                            }
+                            Action::ShowProgress => {
+                                receiver_tx.send(Message::ReceiveNextFrame).unwrap();
+                            }
+                            Action::Describe(m) => {
+                                silence.reset();
+                                encrypted = match check_encryption(&cipher, &m) {
+                                    Ok(encrypted) => encrypted,
+                                    Err(err) => {
+                                        stop(&frame_handler, &session, Reason::Encryption, err);
+                                        let _ = frame_sender.send(Message::Donzo);
+                                        return;
+                                    }
+                                };
+                                match (earlier.take(), m.hash) {
+                                    (Some(earlier), _) if earlier.matches(&m, segment_size) => {
+                                        log.log(format!("Resuming from segment #{}", earlier.base()));
+                                        frame_handler.send(Message::Resume(earlier)).unwrap();
+                                    }
+                                    (earlier, hash) => {
+                                        if earlier.is_some() {
+                                            log.log("The sender has another file, starting over".to_string());
+                                            window = ReceiveWindow::new(window.size);
+                                            receiver_tx.send(Message::ReceiveNextFrame).unwrap();
+                                        }
+                                        if let Some(hash) = hash.filter(|_| options.resume_file.is_some() && ResumeState::is_resumable(&m)) {
+                                            frame_handler.send(Message::Resume(ResumeState::new(hash, segment_size))).unwrap();
+                                        } else if options.resume_file.is_some() && m.compression != Compression::None {
+                                            log.log(NOT_RESUMABLE.to_string());
+                                        }
+                                    }
+                                }
+                                frame_handler.send(Message::Metadata(m.clone())).unwrap();
+                                metadata = Some(m);
+                            }
+                            Action::Accept(segment_offset) => {
+                                match open_segment(&mut cipher, &session, encrypted, frame.clone()) {
+                                    Ok(opened) => {
+                                        // anything already written or too far ahead is just seen again later
+                                        if let Some(ready) = window.accept(segment_offset, opened) {
+                                            silence.reset();
+                                            for frame in ready {
+                                                frame_handler.send(Message::AppendToOutput(frame)).unwrap();
+                                            }
+                                            receiver_tx.send(Message::ReceiveNextFrame).unwrap();
+                                        }
+                                    }
+                                    Err(err) => log.log(err),
                                 }
                             }
-                        } else {
-                            log.log(format!("Unexpected frame {}", frame.get_sequence()));
+                            Action::Verify(hash, signature) => {
+                                let hash = hash.or_else(|| metadata.as_ref().and_then(|m| m.hash));
+                                let signature = signature.or_else(|| metadata.as_ref().and_then(|m| m.signature));
+                                frame_handler.send(Message::Verify(hash, signature)).unwrap();
+                            }
+                            Action::Stopped(reason, text) => {
+                                frame_handler.send(Message::Stopped(reason, text)).unwrap();
+                                receiver_tx.send(Message::Donzo).unwrap();
+                                let _ = frame_sender.send(Message::Donzo);
+                            }
+                            Action::Stop(reason, text) => {
+                                stop(&frame_handler, &session, reason, text);
+                                let _ = frame_sender.send(Message::Donzo);
+                                return;
+                            }
+                            Action::Log(text) => log.log(text),
                         }
                     }
-                    Message::Donzo => {
-                        return;
+                }
+                Message::Verified(hash) => {
+                    match seal_frame(&mut cipher, &session, Frame::new_verified(&session, &hash)) {
+                        Ok(frame) => frame_handler.send(Message::WriteData(frame)).unwrap(),
+                        Err(err) => stop(&frame_handler, &session, Reason::Encryption, err),
                     }
-                    _ => {
+                    frame_handler.send(Message::Donzo).unwrap();
+                    receiver_tx.send(Message::Donzo).unwrap();
+                    frame_sender.send(Message::Donzo).unwrap();
+                }
+                // nothing to confirm until the sender has answered our HELLO
+                Message::Paired if phase == ReceivePhase::Pairing => {
+                    phase = ReceivePhase::Waiting;
+                    silence.reset();
+                    receiver_tx.send(Message::ReceiveNextFrame).unwrap();
+                }
+                // the pairing code is waiting on our operator and the output on being checked
+                Message::Tick if matches!(phase, ReceivePhase::Negotiating | ReceivePhase::Waiting | ReceivePhase::Receiving) => {
+                    if let Some(attempt) = silence.expired() {
+                        log.log(format!("Nothing new from the sender, showing it where we are again ({})", attempt));
+                        receiver_tx.send(Message::ReceiveNextFrame).unwrap();
                     }
                 }
+                Message::Donzo => {
+                    return;
+                }
+                _ => {
+                }
             }
-        });
-        return tx;
+        }
+    }
+
+    async fn receive_broadcast(frame_handler: UnboundedSender<Message>,
+                               log: Log,
+                               frame_sender: UnboundedSender<Message>,
+                               mut rx: UnboundedReceiver<Message>,
+                               receiver_tx: UnboundedSender<Message>,
+                               session: Arc<Session>,
+                               options: TransportOptions) {
+        let mut cipher = Cipher::new(options.passphrase.clone());
+        // nothing's agreed, so the sender's segments are its own size rather than ours
+        let mut segment_size = options.data_size(options.fragment_size);
+        let mut state = BroadcastState::new();
+        let mut metadata: Option<FileMetadata> = None;
+        let mut encrypted = false;
+        let mut verifying = false;
+        loop {
+            match rx.recv().await.expect("No messages") {
+                Message::ReceiveNextFrame => {
+                    log.log("Waiting for broadcast".to_string());
+                }
+                Message::ReceiveFrame(frame) => {
+                    cipher.unlock(session.id()).await;
+                    let frame = match open_frame(&mut cipher, &session, frame) {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            stop(&frame_handler, &session, Reason::Encryption, err);
+                            let _ = frame_sender.send(Message::Donzo);
+                            return;
+                        }
+                    };
+                    match frame.parsed() {
+                        ParsedFrame::Metadata(m) => {
+                            match &mut metadata {
+                                None => {
+                                    encrypted = match check_encryption(&cipher, &m) {
+                                        Ok(encrypted) => encrypted,
+                                        Err(err) => {
+                                            stop(&frame_handler, &session, Reason::Encryption, err);
+                                            let _ = frame_sender.send(Message::Donzo);
+                                            return;
+                                        }
+                                    };
+                                    segment_size = m.segment_size.map_or(segment_size, |size| size as usize);
+                                    let earlier = options.resume_file.as_deref()
+                                        .and_then(ResumeState::load)
+                                        .filter(|earlier| earlier.matches(&m, segment_size));
+                                    match (earlier, m.hash) {
+                                        (Some(earlier), _) => {
+                                            for offset in earlier.written() {
+                                                state.accept(offset, 0);
+                                            }
+                                            log.log(format!("Resuming with {} segments", state.received.len()));
+                                            frame_handler.send(Message::Resume(earlier)).unwrap();
+                                        }
+                                        (None, Some(hash)) if options.resume_file.is_some() && ResumeState::is_resumable(&m) => {
+                                            frame_handler.send(Message::Resume(ResumeState::new(hash, segment_size))).unwrap();
+                                        }
+                                        _ if options.resume_file.is_some() && m.compression != Compression::None => {
+                                            log.log(NOT_RESUMABLE.to_string());
+                                        }
+                                        _ => {}
+                                    }
+                                    frame_handler.send(Message::Metadata(m.clone())).unwrap();
+                                    metadata = Some(m);
+                                }
+                                // the sender only knows the hash after its first pass
+                                Some(known) if known.hash.is_none() => {
+                                    known.hash = m.hash;
+                                    known.signature = m.signature;
+                                }
+                                _ => {}
+                            }
+                        }
+                        ParsedFrame::Segment { .. } if metadata.is_none() => {
+                            log.log("Waiting for metadata".to_string());
+                        }
+                        ParsedFrame::Segment { segment_offset, segment_count, .. } => {
+                            match open_segment(&mut cipher, &session, encrypted, frame.clone()) {
+                                Ok(opened) => {
+                                    if state.accept(segment_offset, segment_count) {
+                                        let received = state.received.len();
+                                        frame_handler.send(Message::WriteToOutput(opened, received)).unwrap();
+                                    }
+                                }
+                                Err(err) => log.log(err),
+                            }
+                        }
+                        ParsedFrame::Done(hash, signature) if state.received.is_empty() && !verifying => {
+                            // an empty input is broadcast as a lone DONE frame
+                            verifying = true;
+                            frame_handler.send(Message::Verify(hash, signature)).unwrap();
+                        }
+                        ParsedFrame::Abort(reason, text) | ParsedFrame::Error(reason, text) => {
+                            frame_handler.send(Message::Stopped(reason, text)).unwrap();
+                            receiver_tx.send(Message::Donzo).unwrap();
+                            let _ = frame_sender.send(Message::Donzo);
+                        }
+                        _ => {}
+                    }
+                    if state.is_complete() && !verifying {
+                        match metadata.as_ref().filter(|m| m.hash.is_some()) {
+                            Some(m) => {
+                                verifying = true;
+                                frame_handler.send(Message::Verify(m.hash, m.signature)).unwrap();
+                            }
+                            None => log.log("Waiting for the sender's hash".to_string()),
+                        }
+                    }
+                }
+                Message::Verified(hash) => {
+                    match seal_frame(&mut cipher, &session, Frame::new_verified(&session, &hash)) {
+                        Ok(frame) => frame_handler.send(Message::WriteData(frame)).unwrap(),
+                        Err(err) => stop(&frame_handler, &session, Reason::Encryption, err),
+                    }
+                    frame_handler.send(Message::Donzo).unwrap();
+                    receiver_tx.send(Message::Donzo).unwrap();
+                    frame_sender.send(Message::Donzo).unwrap();
+                }
+                Message::Donzo => {
+                    return;
+                }
+                _ => {
+                }
+            }
+        }
+    }
+
+    async fn receive_fountain(frame_handler: UnboundedSender<Message>,
+                              log: Log,
+                              frame_sender: UnboundedSender<Message>,
+                              mut rx: UnboundedReceiver<Message>,
+                              receiver_tx: UnboundedSender<Message>,
+                              session: Arc<Session>,
+                              options: TransportOptions) {
+        let mut cipher = Cipher::new(options.passphrase.clone());
+        let mut collector: Option<SymbolCollector> = None;
+        let mut metadata: Option<FileMetadata> = None;
+        let mut encrypted = false;
+        let mut data: Option<Vec<u8>> = None;
+        let mut verifying = false;
+        loop {
+            match rx.recv().await.expect("No messages") {
+                Message::ReceiveNextFrame => {
+                    log.log("Waiting for symbols".to_string());
+                }
+                Message::ReceiveFrame(frame) => {
+                    cipher.unlock(session.id()).await;
+                    let frame = match open_frame(&mut cipher, &session, frame) {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            stop(&frame_handler, &session, Reason::Encryption, err);
+                            let _ = frame_sender.send(Message::Donzo);
+                            return;
+                        }
+                    };
+                    match frame.parsed() {
+                        ParsedFrame::Metadata(m) => {
+                            if metadata.is_none() {
+                                encrypted = match check_encryption(&cipher, &m) {
+                                    Ok(encrypted) => encrypted,
+                                    Err(err) => {
+                                        stop(&frame_handler, &session, Reason::Encryption, err);
+                                        let _ = frame_sender.send(Message::Donzo);
+                                        return;
+                                    }
+                                };
+                                frame_handler.send(Message::Metadata(m.clone())).unwrap();
+                                metadata = Some(m);
+                            }
+                        }
+                        ParsedFrame::Symbol { .. } if metadata.is_none() => {
+                            log.log("Waiting for metadata".to_string());
+                        }
+                        ParsedFrame::Symbol { config, symbol } if data.is_none() => {
+                            let symbol = if encrypted {
+                                match cipher.open_symbol(session.id(), symbol) {
+                                    Ok(symbol) => symbol,
+                                    Err(err) => {
+                                        log.log(err);
+                                        continue;
+                                    }
+                                }
+                            } else {
+                                symbol.to_vec()
+                            };
+                            if collector.is_none() {
+                                collector = SymbolCollector::new(config);
+                            }
+                            if let Some(c) = &mut collector {
+                                data = c.add(&symbol);
+                                frame_handler.send(Message::Progress(c.symbols_received(), c.symbols_needed())).unwrap();
+                            } else {
+                                log.log("Bad symbol configuration".to_string());
+                            }
+                        }
+                        ParsedFrame::Done(hash, signature) if !verifying => {
+                            verifying = true;
+                            frame_handler.send(Message::Verify(hash, signature)).unwrap();
+                        }
+                        ParsedFrame::Verified(hash) => {
+                            frame_sender.send(Message::Verified(hash)).unwrap();
+                            frame_handler.send(Message::Donzo).unwrap();
+                            receiver_tx.send(Message::Donzo).unwrap();
+                            frame_sender.send(Message::Donzo).unwrap();
+                        }
+                        ParsedFrame::Abort(reason, text) | ParsedFrame::Error(reason, text) => {
+                            frame_handler.send(Message::Stopped(reason, text)).unwrap();
+                            receiver_tx.send(Message::Donzo).unwrap();
+                            let _ = frame_sender.send(Message::Donzo);
+                        }
+                        _ => {}
+                    }
+                    if let Some(m) = &metadata {
+                        if let Some(data) = data.take() {
+                            frame_handler.send(Message::AppendToOutput(Frame::new_segment(&session, 0, 1, data))).unwrap();
+                            verifying = true;
+                            frame_handler.send(Message::Verify(m.hash, m.signature)).unwrap();
+                        }
+                    }
+                }
+                Message::Verified(hash) => {
+                    // let a sender that can see us know it can stop
+                    match seal_frame(&mut cipher, &session, Frame::new_verified(&session, &hash)) {
+                        Ok(frame) => frame_handler.send(Message::WriteData(frame)).unwrap(),
+                        Err(err) => stop(&frame_handler, &session, Reason::Encryption, err),
+                    }
+                    frame_handler.send(Message::Donzo).unwrap();
+                    receiver_tx.send(Message::Donzo).unwrap();
+                    frame_sender.send(Message::Donzo).unwrap();
+                }
+                Message::Donzo => {
+                    return;
+                }
+                _ => {
+                }
+            }
+        }
     }
 
     async fn start_sender<I: 'static>(frame_handler: UnboundedSender<Message>,
                                       segment_source_factory: I,
-                                      fragment_size: u16) -> UnboundedSender<Message>
+                                      session: Arc<Session>,
+                                      options: TransportOptions) -> UnboundedSender<Message>
         where I: SegmentSourceFactory
     {
-        let (tx, mut rx) = unbounded_channel();
+        let (tx, rx) = unbounded_channel();
+        let sender_tx = tx.clone();
         tokio::spawn(async move {
-            let mut input = segment_source_factory.create_segment_source();
-            let mut buf = vec![0u8; fragment_size as usize];
-            let num_segments = match input.size() {
-                None => 0,
-                Some(s) => s / fragment_size as usize,
+            // a file is read through to hash it before anything is sent
+            let input = match tokio::task::spawn_blocking(move || segment_source_factory.create_segment_source()).await.unwrap() {
+                Ok(input) => BlockingSource::new(CompressedSource::new(input)),
+                Err(err) => {
+                    stop(&frame_handler, &session, Reason::Io, format!("Failed to read the input: {}", err));
+                    return;
+                }
             };
-            loop {
-                match rx.recv().await.expect("No messages") {
-                    Message::SendFrame(offset) => {
-                        match input.read_segment(offset, &mut buf) {
-                            Ok(size) => {
-                                frame_handler.send(Message::WriteData(Frame::new_segment(offset, num_segments, &buf[0..size]))).unwrap();
-                            }
-                            Err(_) => {
-                                frame_handler.send(Message::WriteData(Frame::new_done())).unwrap();
+            let cipher = Cipher::new(options.passphrase.clone());
+            if options.fountain {
+                Self::send_fountain(frame_handler, input, rx, session, cipher, options).await;
+            } else if options.broadcast {
+                Self::send_broadcast(frame_handler, input, rx, session, cipher, options).await;
+            } else {
+                Self::send_windowed(frame_handler, input, rx, sender_tx, session, cipher, options).await;
+            }
+        });
+        return tx;
+    }
+
+    async fn send_windowed<S: SegmentSource + 'static>(frame_handler: UnboundedSender<Message>,
+                                             input: BlockingSource<S>,
+                                             mut rx: UnboundedReceiver<Message>,
+                                             sender_tx: UnboundedSender<Message>,
+                                             session: Arc<Session>,
+                                             mut cipher: Cipher,
+                                             options: TransportOptions) {
+        let capabilities = options.capabilities();
+        let mut fragment_size = options.data_size(options.fragment_size);
+        let mut num_segments = 0;
+        // peers that never say HELLO get the data uncompressed, if we aren't pairing
+        let mut features = 0;
+        let mut greeted = false;
+        let mut rgb = false;
+        let mut metadata = FileMetadata::default();
+        let mut show_metadata = true;
+        let mut window = SendWindow::new(options.window_size as usize);
+        let mut pairing = Pairing::new();
+        let mut paired = false;
+        // a CTS that turned up before the operator confirmed the pairing code
+        let mut early_window: Option<(usize, Vec<u8>, Option<Hash>)> = None;
+        let mut started = false;
+        let mut done = false;
+        // what stays up while we wait on the receiver, shown again if it goes quiet
+        let mut shown: Option<Frame> = None;
+        let mut silence = Silence::new(options.timeout());
+        let mut ticker = interval(Duration::from_millis(options.frame_interval as u64));
+        loop {
+            select! {
+                message = rx.recv() => match message.expect("No messages") {
+                    Message::Hello(peer, key) if !started => {
+                        silence.reset();
+                        greeted = true;
+                        match capabilities.negotiate(&peer) {
+                            Ok(agreed) => {
+                                features = agreed.features;
+                                rgb = options.show_codes(&frame_handler, agreed.features);
+                                fragment_size = options.data_size(agreed.fragment_size);
+                                window = SendWindow::new(agreed.window_size as usize);
+                                let frame = Frame::new_hello_ack(&session, &agreed, options.pairing.then_some(pairing.public_key()));
+                                shown = Some(frame.clone());
+                                frame_handler.send(Message::WriteData(frame)).unwrap();
+                                match key.filter(|_| options.pairing) {
+                                    Some(key) => frame_handler.send(Message::Pairing(pairing.pair(&key))).unwrap(),
+                                    None if options.pairing => {
+                                        stop(&frame_handler, &session, Reason::Incompatible, NO_PAIRING.to_string());
+                                        return;
+                                    }
+                                    None => {
+                                        frame_handler.send(Message::Log("Not pairing, there's no telling who the receiver is".to_string())).unwrap();
+                                        paired = true;
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::Incompatible, err);
+                                return;
+                            }
+                        }
+                    }
+                    Message::Paired => {
+                        paired = true;
+                        silence.reset();
+                        if let Some((offset, received, resume)) = early_window.take() {
+                            sender_tx.send(Message::SendWindow(offset, received, resume)).unwrap();
+                        }
+                    }
+                    // a receiver that never said HELLO asks for data straight away
+                    Message::SendWindow(..) if !paired && !greeted && options.pairing => {
+                        stop(&frame_handler, &session, Reason::Incompatible, NO_PAIRING.to_string());
+                        return;
+                    }
+                    Message::SendWindow(offset, received, resume) if !paired && !greeted => {
+                        frame_handler.send(Message::Log("The receiver never said HELLO, sending as is without pairing".to_string())).unwrap();
+                        paired = true;
+                        sender_tx.send(Message::SendWindow(offset, received, resume)).unwrap();
+                    }
+                    Message::SendWindow(offset, received, resume) if !paired => {
+                        early_window = Some((offset, received, resume));
+                    }
+                    Message::SendWindow(mut offset, mut received, resume) => {
+                        silence.reset();
+                        if !started {
+                            cipher.unlock(session.id()).await;
+                            if let Err(err) = input.choose(Compression::choose(options.compression, features)).await {
+                                stop(&frame_handler, &session, Reason::from_error(&err), format!("Failed to compress the input: {}", err));
+                                return;
+                            }
+                            metadata = FileMetadata {
+                                segment_size: Some(fragment_size as u32),
+                                encrypted: cipher.is_enabled(),
+                                ..input.metadata()
+                            };
+                            num_segments = match input.size() {
+                                None => 0,
+                                Some(s) => (s + fragment_size - 1) / fragment_size,
+                            };
+                            if let Some(hash) = resume {
+                                if ResumeState::is_resumable(&metadata) && metadata.hash == Some(hash) {
+                                    frame_handler.send(Message::Log(format!("Resuming from segment #{}", offset))).unwrap();
+                                    window.resume(offset);
+                                } else {
+                                    // the receiver starts over once it sees our metadata
+                                    frame_handler.send(Message::Log("The receiver has part of another file, starting over".to_string())).unwrap();
+                                    offset = 0;
+                                    received.clear();
+                                }
+                            }
+                        }
+                        window.acknowledge(offset, &received);
+                        if let Err(err) = window.fill(&input, fragment_size).await {
+                            stop(&frame_handler, &session, Reason::from_error(&err), format!("Failed to read the input: {}", err));
+                            return;
+                        }
+                        started = true;
+                    }
+                    Message::Verified(hash) => {
+                        let sent = metadata.hash.or(input.content_hash()).or(window.hash);
+                        frame_handler.send(Message::Log(delivery_report(sent, hash))).unwrap();
+                        return;
+                    }
+                    Message::Donzo => {
+                        return;
+                    }
+                    _ => {}
+                },
+                _ = ticker.tick() => {
+                    // the pairing code is waiting on our operator, not the receiver
+                    if paired || shown.is_none() {
+                        if let Some(attempt) = silence.expired() {
+                            frame_handler.send(Message::Log(waiting_hint(started, done, attempt))).unwrap();
+                            if let Some(frame) = shown.as_ref().filter(|_| !started || done) {
+                                frame_handler.send(Message::WriteData(frame.clone())).unwrap();
+                            }
+                        }
+                    }
+                    if !started {
+                        continue;
+                    }
+                    if window.is_complete() {
+                        // keep DONE up until the receiver says what it wrote
+                        if !done {
+                            done = true;
+                            silence.reset();
+                            let hash = metadata.hash.or(input.content_hash()).or(window.hash);
+                            let frame = match seal_frame(&mut cipher, &session, done_frame(&session, hash, &options.identity)) {
+                                Ok(frame) => frame,
+                                Err(err) => {
+                                    stop(&frame_handler, &session, Reason::Encryption, err);
+                                    return;
+                                }
+                            };
+                            shown = Some(frame.clone());
+                            frame_handler.send(Message::WriteData(frame)).unwrap();
+                        }
+                        continue;
+                    }
+                    let mut frames = Vec::new();
+                    // until the receiver acknowledges something it might not have seen the metadata
+                    if show_metadata && !window.has_progress() {
+                        match seal_frame(&mut cipher, &session, Frame::new_metadata(&session, &metadata)) {
+                            Ok(frame) => frames.push(frame),
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::Encryption, err);
+                                return;
+                            }
+                        }
+                        show_metadata = false;
+                    }
+                    // each lane gets a different segment, until they run out
+                    let mut first = None;
+                    while frames.len() < options.frames_shown(rgb) {
+                        let (offset, data) = match window.next_missing() {
+                            Some(next) => next,
+                            None => break,
+                        };
+                        if first == Some(offset) {
+                            break;
+                        }
+                        first.get_or_insert(offset);
+                        show_metadata = true;
+                        let data = match cipher.seal_segment(session.id(), offset, data) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::Encryption, err);
+                                return;
+                            }
+                        };
+                        frames.push(Frame::new_segment(&session, offset, num_segments, &data));
+                    }
+                    show(&frame_handler, frames);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Show every segment in turn, forever, for receivers that have no way to answer.
+    ///
+    async fn send_broadcast<S: SegmentSource + 'static>(frame_handler: UnboundedSender<Message>,
+                                              input: BlockingSource<S>,
+                                              mut rx: UnboundedReceiver<Message>,
+                                              session: Arc<Session>,
+                                              mut cipher: Cipher,
+                                              options: TransportOptions) {
+        cipher.unlock(session.id()).await;
+        if let Err(err) = input.choose(options.compression).await {
+            stop(&frame_handler, &session, Reason::from_error(&err), format!("Failed to compress the input: {}", err));
+            return;
+        }
+        let fragment_size = options.data_size(options.fragment_size);
+        let mut num_segments = match input.size() {
+            None => 0,
+            Some(s) => (s + fragment_size - 1) / fragment_size,
+        };
+        let mut metadata = FileMetadata {
+            segment_size: Some(fragment_size as u32),
+            encrypted: cipher.is_enabled(),
+            ..input.metadata()
+        };
+        let mut hasher = Some(ContentHasher::new());
+        let mut offset = 0;
+        let mut ticks: usize = 0;
+        let mut ticker = interval(Duration::from_millis(options.frame_interval as u64));
+        loop {
+            select! {
+                message = rx.recv() => match message.expect("No messages") {
+                    Message::Donzo => {
+                        return;
+                    }
+                    _ => {}
+                },
+                _ = ticker.tick() => {
+                    ticks += 1;
+                    let mut frames = Vec::new();
+                    if ticks % METADATA_INTERVAL == 1 {
+                        match seal_frame(&mut cipher, &session, Frame::new_metadata(&session, &metadata)) {
+                            Ok(frame) => frames.push(frame),
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::Encryption, err);
+                                return;
+                            }
+                        }
+                    }
+                    while frames.len() < options.frames_shown(options.rgb) {
+                        if num_segments > 0 && offset >= num_segments {
+                            // later metadata frames carry the hash of the first pass
+                            if let Some(mut hasher) = hasher.take() {
+                                let hash = input.content_hash().or(Some(hasher.finish()));
+                                metadata = FileMetadata {
+                                    hash,
+                                    signature: sign(hash, &options.identity),
+                                    segment_size: Some(fragment_size as u32),
+                                    encrypted: cipher.is_enabled(),
+                                    ..input.metadata()
+                                };
+                            }
+                            offset = 0;
+                            // the next pass starts on a fresh screen
+                            if !frames.is_empty() {
                                 break;
                             }
                         }
+                        let segment = match input.read_segment(offset, fragment_size).await {
+                            Ok(segment) => segment,
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::from_error(&err), format!("Failed to read the input: {}", err));
+                                return;
+                            }
+                        };
+                        if segment.is_empty() {
+                            let hash = input.content_hash().or(hasher.take().map(|mut hasher| hasher.finish()));
+                            if offset == 0 {
+                                match seal_frame(&mut cipher, &session, done_frame(&session, hash, &options.identity)) {
+                                    Ok(frame) => frame_handler.send(Message::WriteData(frame)).unwrap(),
+                                    Err(err) => stop(&frame_handler, &session, Reason::Encryption, err),
+                                }
+                                return;
+                            }
+                            // the first pass over a source of unknown size tells us how many segments there are
+                            num_segments = offset;
+                            metadata = FileMetadata {
+                                hash,
+                                signature: sign(hash, &options.identity),
+                                segment_size: Some(fragment_size as u32),
+                                encrypted: cipher.is_enabled(),
+                                ..input.metadata()
+                            };
+                            offset = 0;
+                            break;
+                        }
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(&segment);
+                        }
+                        let data = match cipher.seal_segment(session.id(), offset, &segment) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::Encryption, err);
+                                return;
+                            }
+                        };
+                        frames.push(Frame::new_segment(&session, offset, num_segments, &data));
+                        offset += 1;
+                    }
+                    show(&frame_handler, frames);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Read the whole input and show fountain coded symbols for it until told to stop.
+    ///
+    async fn send_fountain<S: SegmentSource + 'static>(frame_handler: UnboundedSender<Message>,
+                                             input: BlockingSource<S>,
+                                             mut rx: UnboundedReceiver<Message>,
+                                             session: Arc<Session>,
+                                             mut cipher: Cipher,
+                                             options: TransportOptions) {
+        cipher.unlock(session.id()).await;
+        if let Err(err) = input.choose(options.compression).await {
+            stop(&frame_handler, &session, Reason::from_error(&err), format!("Failed to compress the input: {}", err));
+            return;
+        }
+        let mut data = Vec::new();
+        let mut offset = 0;
+        loop {
+            match input.read_segment(offset, options.fragment_size as usize).await {
+                Ok(segment) if segment.is_empty() => break,
+                Ok(segment) => data.extend(segment),
+                Err(err) => {
+                    stop(&frame_handler, &session, Reason::from_error(&err), format!("Failed to read the input: {}", err));
+                    return;
+                }
+            }
+            offset += 1;
+        }
+        let mut hasher = ContentHasher::new();
+        hasher.update(&data);
+        let hash = input.content_hash().or(Some(hasher.finish()));
+        // there's nothing to make symbols from
+        if data.is_empty() {
+            match seal_frame(&mut cipher, &session, done_frame(&session, hash, &options.identity)) {
+                Ok(frame) => frame_handler.send(Message::WriteData(frame)).unwrap(),
+                Err(err) => stop(&frame_handler, &session, Reason::Encryption, err),
+            }
+            return;
+        }
+        let metadata = input.metadata();
+        let metadata = FileMetadata {
+            size: metadata.size.or(Some(data.len() as u64)),
+            hash,
+            signature: sign(hash, &options.identity),
+            encrypted: cipher.is_enabled(),
+            ..metadata
+        };
+        let mut symbols = SymbolStream::new(&data, options.data_size(options.fragment_size) as u16);
+        let config = symbols.get_config();
+        let mut ticks: usize = 0;
+        let mut ticker = interval(Duration::from_millis(options.frame_interval as u64));
+        loop {
+            select! {
+                message = rx.recv() => match message.expect("No messages") {
+                    Message::Verified(hash) => {
+                        frame_handler.send(Message::Log(delivery_report(metadata.hash, hash))).unwrap();
+                        return;
                     }
                     Message::Donzo => {
                         return;
                     }
                     _ => {}
+                },
+                _ = ticker.tick() => {
+                    ticks += 1;
+                    let mut frames = Vec::new();
+                    if ticks % METADATA_INTERVAL == 1 {
+                        match seal_frame(&mut cipher, &session, Frame::new_metadata(&session, &metadata)) {
+                            Ok(frame) => frames.push(frame),
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::Encryption, err);
+                                return;
+                            }
+                        }
+                    }
+                    while frames.len() < options.frames_shown(options.rgb) {
+                        let symbol = match cipher.seal_symbol(session.id(), &symbols.next_symbol()) {
+                            Ok(symbol) => symbol,
+                            Err(err) => {
+                                stop(&frame_handler, &session, Reason::Encryption, err);
+                                return;
+                            }
+                        };
+                        frames.push(Frame::new_symbol(&session, &config, &symbol));
+                    }
+                    show(&frame_handler, frames);
                 }
             }
-        });
-        return tx;
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHASES: [ReceivePhase; 6] = [
+        ReceivePhase::Relaying,
+        ReceivePhase::Negotiating,
+        ReceivePhase::Pairing,
+        ReceivePhase::Waiting,
+        ReceivePhase::Receiving,
+        ReceivePhase::Verifying,
+    ];
+    const KEY: PublicKey = [7; 32];
+    const HASH: Hash = [9; 32];
+
+    fn ours() -> Capabilities {
+        return Capabilities::new(500, 400, 8, Compression::Zstd);
+    }
+
+    fn agreed() -> Capabilities {
+        return ours().negotiate(&ours()).unwrap();
+    }
+
+    fn metadata() -> FileMetadata {
+        return FileMetadata {
+            name: "a".to_string(),
+            size: Some(1),
+            ..FileMetadata::default()
+        };
+    }
+
+    fn step(phase: ReceivePhase, frame: &ParsedFrame) -> (ReceivePhase, Vec<Action>) {
+        return phase.step(frame, &ours(), true);
+    }
+
+    fn ignored(phase: ReceivePhase, frame: &ParsedFrame) -> bool {
+        return step(phase, frame) == (phase, Vec::new());
+    }
+
+    #[test]
+    fn relaying_passes_the_receiver_on() {
+        let relaying = ReceivePhase::Relaying;
+        assert_eq!(step(relaying, &ParsedFrame::Hello(ours(), Some(KEY))), (relaying, vec![Action::PassHello(ours(), Some(KEY))]));
+        assert_eq!(step(relaying, &ParsedFrame::Cts { segment_offset: 3, received: &[1] }), (relaying, vec![Action::PassWindow(3, vec![1], None)]));
+        assert_eq!(step(relaying, &ParsedFrame::Resume { hash: HASH, segment_offset: 3, received: &[] }), (relaying, vec![Action::PassWindow(3, Vec::new(), Some(HASH))]));
+        assert_eq!(step(relaying, &ParsedFrame::Verified(HASH)), (relaying, vec![Action::PassVerified(HASH)]));
+        assert!(ignored(relaying, &ParsedFrame::HelloAck(agreed(), Some(KEY))));
+        assert!(ignored(relaying, &ParsedFrame::Metadata(metadata())));
+        assert!(ignored(relaying, &ParsedFrame::Done(Some(HASH), None)));
+    }
+
+    #[test]
+    fn hello_ack_with_a_key_starts_pairing() {
+        let (phase, actions) = step(ReceivePhase::Negotiating, &ParsedFrame::HelloAck(agreed(), Some(KEY)));
+        assert_eq!(phase, ReceivePhase::Pairing);
+        assert_eq!(actions, vec![Action::Agree(agreed()), Action::Pair(KEY)]);
+    }
+
+    #[test]
+    fn hello_ack_without_a_key_needs_no_pairing() {
+        let hello_ack = ParsedFrame::HelloAck(agreed(), None);
+        let (phase, actions) = step(ReceivePhase::Negotiating, &hello_ack);
+        assert_eq!(phase, ReceivePhase::Negotiating);
+        assert!(matches!(actions[..], [Action::Stop(Reason::Incompatible, _)]));
+        let (phase, actions) = ReceivePhase::Negotiating.step(&hello_ack, &ours(), false);
+        assert_eq!(phase, ReceivePhase::Waiting);
+        assert_eq!(actions.first(), Some(&Action::Agree(agreed())));
+        assert_eq!(actions.last(), Some(&Action::ShowProgress));
+        // a key we aren't going to check is no reason to wait for our operator
+        let (phase, _) = ReceivePhase::Negotiating.step(&ParsedFrame::HelloAck(agreed(), Some(KEY)), &ours(), false);
+        assert_eq!(phase, ReceivePhase::Waiting);
+    }
+
+    #[test]
+    fn mismatched_hello_ack_stops() {
+        let newer = Capabilities {
+            version: ours().version + 1,
+            ..agreed()
+        };
+        let (phase, actions) = step(ReceivePhase::Negotiating, &ParsedFrame::HelloAck(newer, Some(KEY)));
+        assert_eq!(phase, ReceivePhase::Negotiating);
+        assert!(matches!(actions[..], [Action::Stop(Reason::Incompatible, _)]));
+        let (_, actions) = step(ReceivePhase::Negotiating, &ParsedFrame::Hello(newer, Some(KEY)));
+        assert!(matches!(actions[..], [Action::Stop(Reason::Incompatible, _)]));
+    }
+
+    #[test]
+    fn metadata_then_segments_then_done() {
+        let (phase, actions) = step(ReceivePhase::Waiting, &ParsedFrame::Metadata(metadata()));
+        assert_eq!((phase, actions), (ReceivePhase::Receiving, vec![Action::Describe(metadata())]));
+        let segment = ParsedFrame::Segment { segment_offset: 2, segment_count: 4, data: &[1, 2] };
+        assert_eq!(step(ReceivePhase::Receiving, &segment), (ReceivePhase::Receiving, vec![Action::Accept(2)]));
+        let (phase, actions) = step(ReceivePhase::Receiving, &ParsedFrame::Done(Some(HASH), None));
+        assert_eq!((phase, actions), (ReceivePhase::Verifying, vec![Action::Verify(Some(HASH), None)]));
+    }
+
+    #[test]
+    fn segments_before_metadata_are_dropped() {
+        let segment = ParsedFrame::Segment { segment_offset: 0, segment_count: 4, data: &[1] };
+        let (phase, actions) = step(ReceivePhase::Waiting, &segment);
+        assert_eq!(phase, ReceivePhase::Waiting);
+        assert!(matches!(actions[..], [Action::Log(_)]));
+    }
+
+    #[test]
+    fn an_empty_input_is_done_without_metadata() {
+        let (phase, actions) = step(ReceivePhase::Waiting, &ParsedFrame::Done(None, None));
+        assert_eq!((phase, actions), (ReceivePhase::Verifying, vec![Action::Verify(None, None)]));
+    }
+
+    #[test]
+    fn duplicates_change_nothing() {
+        assert!(ignored(ReceivePhase::Pairing, &ParsedFrame::HelloAck(agreed(), Some(KEY))));
+        assert!(ignored(ReceivePhase::Waiting, &ParsedFrame::HelloAck(agreed(), Some(KEY))));
+        assert!(ignored(ReceivePhase::Receiving, &ParsedFrame::Metadata(metadata())));
+        assert!(ignored(ReceivePhase::Verifying, &ParsedFrame::Done(Some(HASH), None)));
+        assert!(ignored(ReceivePhase::Verifying, &ParsedFrame::Segment { segment_offset: 0, segment_count: 1, data: &[1] }));
+    }
+
+    #[test]
+    fn out_of_order_frames_change_nothing() {
+        for phase in [ReceivePhase::Negotiating, ReceivePhase::Pairing] {
+            assert!(ignored(phase, &ParsedFrame::Metadata(metadata())));
+            assert!(ignored(phase, &ParsedFrame::Done(Some(HASH), None)));
+            assert!(ignored(phase, &ParsedFrame::Segment { segment_offset: 0, segment_count: 1, data: &[1] }));
+        }
+        for phase in [ReceivePhase::Receiving, ReceivePhase::Verifying] {
+            assert!(ignored(phase, &ParsedFrame::HelloAck(agreed(), Some(KEY))));
+        }
+        // VERIFIED is what we show once we're done, seeing it while receiving ends nothing
+        for phase in &PHASES[1..] {
+            assert!(ignored(*phase, &ParsedFrame::Verified(HASH)));
+        }
+    }
+
+    #[test]
+    fn stale_cts_is_not_passed_on() {
+        for phase in &PHASES[1..] {
+            assert!(ignored(*phase, &ParsedFrame::Cts { segment_offset: 0, received: &[] }));
+            assert!(ignored(*phase, &ParsedFrame::Resume { hash: HASH, segment_offset: 0, received: &[] }));
+        }
+    }
+
+    #[test]
+    fn abort_and_error_stop_every_phase() {
+        for phase in PHASES {
+            for frame in [ParsedFrame::Abort(Reason::Cancelled, "Cancelled".to_string()), ParsedFrame::Error(Reason::DiskFull, "full".to_string())] {
+                let (next, actions) = step(phase, &frame);
+                assert_eq!(next, phase);
+                assert!(matches!(actions[..], [Action::Stopped(..)]));
+            }
+        }
+    }
+}